@@ -0,0 +1,75 @@
+//! Several writers contending for space in a small channel.
+//!
+//! Reports throughput and the number of context switches, which is mostly
+//! a measure of how many blocked writers get woken for nothing.
+//!
+//! Run with `cargo bench --bench mpsc`.
+
+use std::{
+    hint::black_box,
+    sync::Arc,
+    thread::spawn,
+    time::{Duration, Instant},
+};
+
+use gyoll::base::{Channel, ChannelFactory};
+
+const CAPACITY: usize = 1 << 16;
+const CHUNK: usize = 1 << 13;
+const BYTES_PER_WRITER: usize = 1 << 28;
+
+fn main() {
+    for writers in [1, 2, 4, 8, 16] {
+        let (elapsed, switches) = run(writers);
+        let bytes = (writers * BYTES_PER_WRITER) as f64;
+        println!(
+            "mpsc {} writers: {:8.3} GB/s {:10} context switches ({:.1} per MB)",
+            writers,
+            bytes / elapsed.as_secs_f64() * 1e-9,
+            switches,
+            switches as f64 / (bytes / (1 << 20) as f64),
+        );
+    }
+}
+
+fn run(writers: usize) -> (Duration, i64) {
+    let channel = Arc::new(Channel::new(CAPACITY));
+    let mut rx = channel.receiver();
+
+    let before = context_switches();
+    let t0 = Instant::now();
+    let handles: Vec<_> = (0..writers)
+        .map(|_| {
+            let mut tx = channel.sender();
+            spawn(move || {
+                for _ in 0..BYTES_PER_WRITER / CHUNK {
+                    tx.map(CHUNK).unwrap()[0] = 1;
+                }
+            })
+        })
+        .collect();
+
+    // The reader does a little work on every byte so that it's the
+    // bottleneck and the writers spend their time blocked on space.
+    let mut remaining = writers * BYTES_PER_WRITER;
+    let mut sum = 0u64;
+    while remaining > 0 {
+        if let Some(region) = rx.recv() {
+            sum += region.iter().map(|&b| b as u64).sum::<u64>();
+            remaining -= region.len();
+        }
+    }
+    black_box(sum);
+    for h in handles {
+        h.join().unwrap();
+    }
+    let elapsed = t0.elapsed();
+    (elapsed, context_switches() - before)
+}
+
+/// Voluntary and involuntary context switches of the whole process so far.
+fn context_switches() -> i64 {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    usage.ru_nvcsw + usage.ru_nivcsw
+}
@@ -1,8 +1,15 @@
 //! This is meant to be a practical (though simulated) example of streaming
 //! raw video data to disk
+//!
+//! ```text
+//! cargo run --release --example disk_streaming -- [OUTPUT] [PREALLOCATE_BYTES]
+//! ```
+//!
+//! OUTPUT defaults to `disk_streaming.raw` in the temp dir, which is often a
+//! tmpfs that doesn't support O_DIRECT. Nothing is preallocated unless
+//! PREALLOCATE_BYTES is given.
 
 use std::{
-    io::Write,
     panic,
     path::PathBuf,
     process,
@@ -11,10 +18,13 @@ use std::{
         Arc,
     },
     thread::{self, sleep, spawn, JoinHandle},
-    time::{Duration, Instant},
+    time::Duration,
 };
 
-use gyoll::base::{Channel, ChannelFactory, Receiver, Sender};
+use gyoll::{
+    base::{Channel, ChannelFactory, Receiver, Sender},
+    io::DirectSink,
+};
 use log::{debug, info};
 
 struct Ticker {
@@ -35,7 +45,7 @@ fn ticker() -> Ticker {
     }
 }
 
-fn producer(tx: Sender, name: &'static str, payload_size: usize) -> (JoinHandle<()>, &str) {
+fn producer(tx: Sender, name: &'static str, payload_size: usize) -> (JoinHandle<()>, &'static str) {
     let mut tx = tx;
     let th = thread::Builder::new()
         .name(name.into())
@@ -65,46 +75,25 @@ fn producer(tx: Sender, name: &'static str, payload_size: usize) -> (JoinHandle<
     (th, name)
 }
 
-fn consumer(rx: Receiver, name: &'static str) -> (JoinHandle<()>, &str) {
+fn consumer(
+    rx: Receiver,
+    name: &'static str,
+    path: PathBuf,
+    preallocate: u64,
+) -> (JoinHandle<()>, &'static str) {
     let mut rx = rx;
     let th = thread::Builder::new()
         .name(name.into())
         .spawn(move || {
             info!("{}: Entering Reader", name);
-            let path = [std::env::temp_dir(), "disk_streaming.raw".into()]
-                .iter()
-                .collect::<PathBuf>();
-            let mut read_bytes = 0;
-            let t0 = Instant::now();
-            {
-                let mut out =
-                    std::fs::File::create(path.clone()).expect("Could not open output file");
-                // let mut out = std::io::BufWriter::new(out);
-                let mut first = None;
-                while rx.is_open() {
-                    while let Some(available) = rx.next() {
-                        if first.is_none() {
-                            first = Some(available.as_ptr() as isize);
-                        }
-                        out.write_all(&available).expect("Write failed");
-                        read_bytes += available.len();
-                        debug!(
-                            "{}: 0x{:0x} {:4}:{:4}",
-                            name,
-                            available.as_ptr() as isize,
-                            available.as_ptr() as isize - first.unwrap(),
-                            available.as_ptr() as isize - first.unwrap() + available.len() as isize,
-                        );
-                    }
-                }
-            }
-            let dt = Instant::now() - t0;
+            let sink = DirectSink::create(&path, preallocate).expect("Could not open output file");
+            let report = sink.write_from(&mut rx).expect("Write failed");
             info!(
                 "{}: Read - total: {} ({} GB). Wrote {} GB/s to {}",
                 name,
-                read_bytes,
-                (read_bytes as f32) * 1e-9,
-                (read_bytes as f64) * 1e-9 / dt.as_secs_f64(),
+                report.bytes,
+                (report.bytes as f32) * 1e-9,
+                report.throughput() * 1e-9,
                 path.to_string_lossy()
             );
             info!("{}: Exiting Reader", name);
@@ -125,12 +114,22 @@ fn main() {
         process::exit(1);
     }));
 
+    let mut args = std::env::args().skip(1);
+    let path = args
+        .next()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join("disk_streaming.raw"));
+    let preallocate = args
+        .next()
+        .map(|n| n.parse().expect("PREALLOCATE_BYTES must be a number"))
+        .unwrap_or(0);
+
     // Get down to business
 
     let ch = Arc::new(Channel::new(1 << 29)); // 0.5 GB
 
     let threads = [
-        consumer(ch.receiver(), "R0"),
+        consumer(ch.receiver(), "R0", path, preallocate),
         producer(ch.sender(), "W0", 1 << 23), // 2048x2048xu16 = 1<<23
     ];
 
@@ -141,6 +140,6 @@ fn main() {
     ticker.running.store(false, Ordering::SeqCst);
 
     for (t, name) in threads {
-        t.join().expect(&format!("{} failed", name));
+        t.join().unwrap_or_else(|_| panic!("{} failed", name));
     }
 }
@@ -29,7 +29,7 @@ fn ticker() -> Ticker {
     }
 }
 
-fn producer(tx: Sender, name: &'static str, payload_size: usize) -> (JoinHandle<()>, &str) {
+fn producer(tx: Sender, name: &'static str, payload_size: usize) -> (JoinHandle<()>, &'static str) {
     let mut tx = tx;
     let th = thread::Builder::new()
         .name(name.into())
@@ -60,7 +60,7 @@ fn producer(tx: Sender, name: &'static str, payload_size: usize) -> (JoinHandle<
     (th, name)
 }
 
-fn consumer(rx: Receiver, name: &'static str) -> (JoinHandle<()>, &str) {
+fn consumer(rx: Receiver, name: &'static str) -> (JoinHandle<()>, &'static str) {
     let mut rx = rx;
     let th = thread::Builder::new()
         .name(name.into())
@@ -129,6 +129,6 @@ fn main() {
     ticker.running.store(false, Ordering::SeqCst);
 
     for (t, name) in threads {
-        t.join().expect(&format!("{} failed", name));
+        t.join().unwrap_or_else(|_| panic!("{} failed", name));
     }
 }
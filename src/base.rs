@@ -1,10 +1,32 @@
 mod channel;
 mod counter;
-mod cursor;
-mod receiver;
+mod error;
+mod group;
+pub(crate) mod cursor;
+pub(crate) mod receiver;
+mod record;
 mod region;
+mod select;
 mod sender;
+mod snapshot;
+mod stats;
+mod sync;
+mod validate;
+mod wait;
 
-pub use channel::{channel, Channel, ChannelFactory};
+#[cfg(target_os = "linux")]
+pub(crate) use channel::map_shared;
+pub use cursor::Position;
+pub use channel::{channel, Channel, ChannelFactory, ALIGNMENT};
+pub use error::Error;
+pub use group::{ConsumerGroup, GroupReceiver, GroupRecord};
 pub use receiver::Receiver;
+pub use record::{Meta, Record};
+pub use region::{MutBatch, MutRegion, Region, RegionMut};
+pub(crate) use select::Signal;
+pub use select::Select;
 pub use sender::Sender;
+pub use snapshot::{GroupSnapshot, ReceiverSnapshot, ReceiverState, Snapshot};
+pub use stats::{GroupStats, ReceiverStats, Stats};
+pub use validate::{Invariant, Report, Violation};
+pub use wait::WaitStrategy;
@@ -1,25 +1,57 @@
 use std::{
     alloc::{self, Layout},
-    collections::{btree_set::Intersection, hash_map::Entry, HashMap, HashSet},
+    collections::{btree_set::Intersection, hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
     fmt::{Debug, Display},
     hash::Hash,
+    io,
     ptr::NonNull,
     sync::Arc,
 };
 
-use parking_lot::{Condvar, Mutex, RwLock};
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use log::warn;
+use parking_lot::RwLock;
 
 use super::{
     counter::Counter,
-    cursor::{BegCursor, EndCursor, Interval},
+    cursor::{BegCursor, EndCursor, Interval, Position},
+    group::{ConsumerGroup, GroupEntry},
     receiver::Receiver,
-    sender::Sender,
+    record::RecordEntry,
+    select::Signal,
+    sender::{collide, Sender},
+    snapshot::{GroupSnapshot, ReceiverSnapshot, ReceiverState, Snapshot},
+    error::Error,
+    stats::{Counters, GroupStats, ReceiverStats, Stats},
+    sync::{self, AtomicBool, Condvar, Mutex, Ordering, ThreadId},
+    validate::{Invariant, Report, Violation},
+    wait::WaitStrategy,
 };
 
+/// Alignment of the channel's buffer.
+///
+/// Matches the page size and the block size needed for direct I/O.
+pub const ALIGNMENT: usize = 1 << 12;
+
+/// Where the channel's buffer comes from.
+#[derive(Debug)]
+pub(crate) enum Backing {
+    Heap,
+    /// Shared memory that other processes can map.
+    #[cfg(target_os = "linux")]
+    Memfd(OwnedFd),
+    /// Shared memory that another process created and this one mapped.
+    #[cfg(target_os = "linux")]
+    Mapped,
+}
+
 #[derive(Debug)]
 pub(crate) struct RawChannel {
     pub(crate) ptr: NonNull<u8>,
     pub(crate) capacity: usize,
+    pub(crate) backing: Backing,
 
     /// when closing the channel, we stop accepting writes
     pub(crate) is_accepting_writes: bool,
@@ -32,50 +64,482 @@ pub(crate) struct RawChannel {
 
     pub(crate) outstanding_writes: HashSet<Interval>,
     pub(crate) outstanding_reads: Counter<BegCursor>,
+
+    /// Stream position of the start of each live cycle. Positions skip the
+    /// bytes past the high mark.
+    pub(crate) cycle_bases: BTreeMap<isize, u64>,
+
+    pub(crate) receivers: BTreeMap<u64, ReceiverEntry>,
+    /// The id of the receiver allowed to modify what it reads, if any.
+    pub(crate) exclusive: Option<u64>,
+
+    pub(crate) groups: HashMap<u64, GroupEntry>,
+    pub(crate) next_group_id: u64,
+
+    /// Every reserved region that hasn't been released, in stream order.
+    pub(crate) records: VecDeque<RecordEntry>,
+    pub(crate) next_seq: u64,
+    /// How many records at the front of `records` have been numbered.
+    pub(crate) numbered: usize,
+    pub(crate) next_receiver_id: u64,
+
+    pub(crate) counters: Counters,
+
+    /// Signals of the `Select`s waiting on this channel.
+    pub(crate) watchers: Vec<Arc<Signal>>,
+
+    /// Writers blocked until the readers free their reservation, in the
+    /// order they reserved.
+    pub(crate) waiting_writers: VecDeque<WaitingWriter>,
+    pub(crate) next_ticket: u64,
+
+    pub(crate) wait_strategy: WaitStrategy,
+
+    /// The thread that reserved each outstanding write, by where it starts.
+    pub(crate) reserved_by: HashMap<BegCursor, ThreadId>,
+
+    /// Set once an invariant was found broken. See `Channel::poison`.
+    pub(crate) poisoned: Option<Arc<Report>>,
+    pub(crate) poison_on_panic: bool,
+}
+
+/// What the channel knows about one of its receivers.
+#[derive(Debug)]
+pub(crate) struct ReceiverEntry {
+    /// The read position.
+    pub(crate) cur: EndCursor,
+    /// Where the regions acquired but not yet released start, and the
+    /// thread that acquired each.
+    pub(crate) held: Vec<(BegCursor, ThreadId)>,
+    /// The receiver this one waits on, if any. See `Receiver::dependent`.
+    pub(crate) upstream: Option<u64>,
+    /// True while blocked waiting for data.
+    pub(crate) waiting: bool,
+}
+
+/// A writer blocked in `Sender::reserve`.
+#[derive(Debug)]
+pub(crate) struct WaitingWriter {
+    pub(crate) ticket: u64,
+    /// The end of the writer's reservation.
+    pub(crate) end: EndCursor,
+    pub(crate) wake: Arc<Condvar>,
 }
 
+// SAFETY: `ptr` is the buffer allocated in `new` and freed in `drop`, and
+// nothing else owns it. The bytes behind it are only reached through
+// regions, whose intervals are handed out without overlap while holding the
+// channel's lock, so the pointer can move to another thread with the rest of
+// the channel.
+unsafe impl Send for RawChannel {}
+
 impl Display for RawChannel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "write:( {write} {closed}) read:( {read} ) outstanding writes: {outw:?} reads:{outr:?}",
-            write = self.writes,
-            closed= if self.is_accepting_writes {""} else {"CLOSED"},
-            read = self.reads,
-            outw = self.outstanding_writes,
-            outr = self.outstanding_reads
-        )
+        // This also shows up in assertion messages, so it mustn't panic when
+        // the cursors are inconsistent. Fall back to the raw cursors then.
+        match self.snapshot() {
+            Some(snapshot) => write!(f, "{}", snapshot),
+            None => write!(
+                f,
+                "write:( {} ) read:( {} ) cycles:{:?} (inconsistent)",
+                self.writes, self.reads, self.cycle_bases
+            ),
+        }
     }
 }
 
 impl RawChannel {
     fn new(nbytes: usize) -> Self {
-        // Align to 4096
-        let layout = Layout::from_size_align(nbytes, 1 << 12).unwrap();
+        let layout = Layout::from_size_align(nbytes, ALIGNMENT).unwrap();
         let ptr = unsafe { std::alloc::alloc(layout) };
         let ptr = match NonNull::new(ptr) {
             Some(p) => p,
             None => std::alloc::handle_alloc_error(layout),
         };
+        Self::with_buffer(ptr, nbytes, Backing::Heap)
+    }
 
+    /// Allocates the buffer in an anonymous memory file so it can be shared
+    /// with other processes.
+    #[cfg(target_os = "linux")]
+    fn memfd(nbytes: usize) -> io::Result<Self> {
+        let fd = unsafe { libc::memfd_create(c"gyoll".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        if unsafe { libc::ftruncate(fd.as_raw_fd(), nbytes as _) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ptr = map_shared(fd.as_raw_fd(), nbytes, libc::PROT_READ | libc::PROT_WRITE)?;
+        Ok(Self::with_buffer(ptr, nbytes, Backing::Memfd(fd)))
+    }
+
+    /// Takes over a buffer mapped from another process, starting the stream
+    /// at `start` so that it lines up with the other process's channel.
+    #[cfg(target_os = "linux")]
+    fn mapped(ptr: NonNull<u8>, nbytes: usize, start: usize) -> Self {
+        let mut ch = Self::with_buffer(ptr, nbytes, Backing::Mapped);
+        let cur = BegCursor {
+            cycle: 0,
+            offset: start as isize,
+        };
+        ch.writes = Interval {
+            beg: cur,
+            end: cur.into(),
+            high_mark: None,
+        };
+        ch.reads = ch.writes;
+        ch
+    }
+
+    fn with_buffer(ptr: NonNull<u8>, nbytes: usize, backing: Backing) -> Self {
         Self {
             ptr,
             capacity: nbytes,
+            backing,
             is_accepting_writes: true,
             writes: Interval::default(),
             reads: Interval::default(),
             outstanding_writes: HashSet::new(),
             outstanding_reads: Counter::new(),
+            cycle_bases: BTreeMap::from([(0, 0)]),
+            receivers: BTreeMap::new(),
+            next_receiver_id: 0,
+            exclusive: None,
+            groups: HashMap::new(),
+            next_group_id: 0,
+            records: VecDeque::new(),
+            next_seq: 0,
+            numbered: 0,
+            counters: Counters::default(),
+            watchers: Vec::new(),
+            waiting_writers: VecDeque::new(),
+            next_ticket: 0,
+            wait_strategy: WaitStrategy::default(),
+            reserved_by: HashMap::new(),
+            poisoned: None,
+            poison_on_panic: false,
+        }
+    }
+
+    /// Number of bytes in the stream before `cur`, or None if the channel
+    /// doesn't know where its cycle starts.
+    ///
+    /// The channel keeps the start of every cycle from the one before the
+    /// read tail's, so this only fails if the cursors are broken.
+    pub(crate) fn position(&self, cur: EndCursor) -> Option<u64> {
+        Some(self.cycle_bases.get(&cur.cycle)? + cur.offset as u64)
+    }
+
+    /// Like `position`, failing with a violation instead.
+    pub(crate) fn known_position(&self, cur: EndCursor) -> Result<u64, Violation> {
+        self.position(cur).ok_or_else(|| {
+            let detail = format!("no base for cursor {} in {:?}", cur, self.cycle_bases);
+            Violation::new(Invariant::KnownCycles, detail)
+        })
+    }
+
+    /// Converts a cursor to a position in the stream.
+    ///
+    /// A cursor at the high mark and one at the start of the next cycle
+    /// name the same place, so they convert to the same position.
+    pub(crate) fn to_position(&self, cur: impl Into<EndCursor>) -> Option<Position> {
+        self.position(cur.into()).map(Position)
+    }
+
+    /// Adds what the read tail moved past since `before` to the released
+    /// bytes.
+    pub(crate) fn count_released(&mut self, before: BegCursor) {
+        let moved = self.position(self.reads.beg.into()).zip(self.position(before.into()));
+        if let Some((after, before)) = moved {
+            self.counters.released += after - before;
+        }
+    }
+
+    /// The index of the record reserved as `interval`.
+    pub(crate) fn find_record(&self, interval: &Interval) -> Option<usize> {
+        let i = self.records.partition_point(|r| r.interval.beg < interval.beg);
+        (self.records.get(i)?.interval == *interval).then_some(i)
+    }
+
+    /// Forgets records every receiver has read past.
+    pub(crate) fn prune_records(&mut self) {
+        while let Some(r) = self.records.front() {
+            if BegCursor::from(r.interval.end) > self.reads.beg {
+                break;
+            }
+            self.records.pop_front();
+            self.numbered -= 1;
+        }
+    }
+
+    /// Numbers the records the read head moved past.
+    ///
+    /// Records are numbered as they're published rather than when they're
+    /// reserved, so one that's truncated to nothing doesn't leave a gap.
+    pub(crate) fn number_records(&mut self) {
+        while let Some(r) = self.records.get_mut(self.numbered) {
+            if r.interval.end > self.reads.end {
+                break;
+            }
+            r.seq = self.next_seq;
+            self.next_seq += 1;
+            self.numbered += 1;
+        }
+    }
+
+    /// True if every cursor has a position.
+    pub(crate) fn is_consistent(&self) -> bool {
+        let known = |cycle: isize| self.cycle_bases.contains_key(&cycle);
+        [
+            self.writes.beg.cycle,
+            self.writes.end.cycle,
+            self.reads.beg.cycle,
+            self.reads.end.cycle,
+        ]
+        .into_iter()
+        .chain(self.outstanding_writes.iter().map(|i| i.beg.cycle))
+        .chain(self.receivers.values().map(|r| r.cur.cycle))
+        .all(known)
+    }
+
+    /// Records where the cycle started by the wrapped interval `inc` begins.
+    pub(crate) fn start_cycle(&mut self, inc: &Interval) -> Result<(), Violation> {
+        let prev = EndCursor {
+            cycle: inc.beg.cycle - 1,
+            offset: inc.high_mark.unwrap(),
+        };
+        let base = self.known_position(prev)?;
+        self.cycle_bases.insert(inc.beg.cycle, base);
+        Ok(())
+    }
+
+    /// Forgets cycles no cursor can point into any more. A receiver's
+    /// cursor may still sit at the high mark of the cycle before the tail.
+    pub(crate) fn prune_cycles(&mut self) {
+        let oldest = self.reads.beg.cycle - 1;
+        self.cycle_bases.retain(|&cycle, _| cycle >= oldest);
+    }
+
+    /// The channel's state, or None if a cursor has no position.
+    pub(crate) fn snapshot(&self) -> Option<Snapshot> {
+        let pos = |cur: EndCursor| self.position(cur);
+        let range = |i: &Interval| Some(pos(i.beg.into())?..pos(i.end)?);
+        let mut outstanding_writes: Vec<_> = self.outstanding_writes.iter().collect();
+        outstanding_writes.sort();
+        Some(Snapshot {
+            capacity: self.capacity,
+            closed: !self.is_accepting_writes,
+            reads: range(&self.reads)?,
+            writes: pos(self.writes.beg.into())?..pos(self.writes.end)?,
+            outstanding_writes: outstanding_writes.into_iter().map(range).collect::<Option<_>>()?,
+            skipped: self.skipped()?,
+            receivers: self
+                .receivers
+                .iter()
+                .map(|(&id, r)| {
+                    Some(ReceiverSnapshot {
+                        id,
+                        position: pos(r.cur)?,
+                        state: if !r.held.is_empty() {
+                            ReceiverState::Reading(r.held.len())
+                        } else if r.waiting {
+                            ReceiverState::Waiting
+                        } else {
+                            ReceiverState::Idle
+                        },
+                    })
+                })
+                .collect::<Option<_>>()?,
+            groups: self
+                .groups_by_id()
+                .map(|(id, g)| {
+                    Some(GroupSnapshot {
+                        id,
+                        released: pos(g.floor.into())?,
+                        claimed: pos(g.next)?,
+                    })
+                })
+                .collect::<Option<_>>()?,
+        })
+    }
+
+    /// The consumer groups in the order they were created.
+    fn groups_by_id(&self) -> impl Iterator<Item = (u64, &GroupEntry)> {
+        let mut groups: Vec<_> = self.groups.iter().map(|(&id, g)| (id, g)).collect();
+        groups.sort_by_key(|&(id, _)| id);
+        groups.into_iter()
+    }
+
+    /// Bytes past the high mark of the read tail's cycle, if the write head
+    /// has wrapped past it.
+    fn skipped(&self) -> Option<u64> {
+        // Once the readers catch up, the tail can be a cycle ahead.
+        let tail = self.read_tail();
+        if self.writes.end.cycle <= tail.cycle {
+            return Some(0);
+        }
+        let next = self.cycle_bases.get(&(tail.cycle + 1))?;
+        let high_mark = next - self.cycle_bases.get(&tail.cycle)?;
+        Some(self.capacity as u64 - high_mark)
+    }
+
+    /// A copy of the counters, or None if a cursor has no position.
+    pub(crate) fn stats(&self) -> Option<Stats> {
+        let head = self.position(self.reads.end)?;
+        Some(Stats {
+            committed: self.counters.committed,
+            released: self.counters.released,
+            occupancy: self.occupancy()?,
+            peak_occupancy: self.counters.peak_occupancy,
+            writer_waits: self.counters.writer_waits,
+            writer_wait_time: self.counters.writer_wait_time,
+            wraps: self.counters.wraps,
+            receivers: self
+                .receivers
+                .iter()
+                .map(|(&id, r)| {
+                    Some(ReceiverStats {
+                        id,
+                        lag: head - self.position(r.cur)?,
+                    })
+                })
+                .collect::<Option<_>>()?,
+            groups: self
+                .groups_by_id()
+                .map(|(id, g)| {
+                    Some(GroupStats {
+                        id,
+                        lag: head - self.position(g.next)?,
+                    })
+                })
+                .collect::<Option<_>>()?,
+        })
+    }
+
+    /// True if some receiver waits on another one.
+    pub(crate) fn has_dependents(&self) -> bool {
+        self.receivers.values().any(|r| r.upstream.is_some())
+    }
+
+    /// Tells every `Select` waiting on the channel that something changed.
+    pub(crate) fn wake_watchers(&self) {
+        for watcher in &self.watchers {
+            watcher.notify();
+        }
+    }
+
+    /// True if a region ending at `end` would overwrite bytes the readers
+    /// haven't released yet.
+    pub(crate) fn collides_with_readers(&self, end: &EndCursor) -> bool {
+        // The read tail can only move forward in `read_tail`, so there's no
+        // need to look it up when the region is clear of it as it is.
+        collide(end, &self.reads.beg) && collide(end, &self.read_tail())
+    }
+
+    /// The read tail as far as writers are concerned.
+    ///
+    /// Once the readers catch up with the writers the tail can be left at
+    /// the end of the last byte written in its cycle. That's the same place
+    /// as the start of the next cycle, and measuring from there keeps a
+    /// region that wraps from waiting on bytes that will never be read.
+    fn read_tail(&self) -> BegCursor {
+        let r = self.reads.beg;
+        let at_end = match self.cycle_bases.get(&(r.cycle + 1)) {
+            Some(&next) => self
+                .cycle_bases
+                .get(&r.cycle)
+                .is_some_and(|&base| base + r.offset as u64 == next),
+            // Nothing was written past the tail yet.
+            None => EndCursor::from(r) == self.writes.end,
+        };
+        if at_end {
+            BegCursor {
+                cycle: r.cycle + 1,
+                offset: 0,
+            }
+        } else {
+            r
+        }
+    }
+
+    /// Wakes the blocked writers whose reservations the readers have freed,
+    /// or all of them once the channel is closed.
+    ///
+    /// Writers wait in stream order, so the first one still colliding with
+    /// the read tail means the rest do too.
+    pub(crate) fn wake_writers(&mut self) {
+        while let Some(w) = self.waiting_writers.front() {
+            if self.is_accepting_writes && self.collides_with_readers(&w.end) {
+                break;
+            }
+            w.wake.notify_one();
+            self.waiting_writers.pop_front();
+        }
+    }
+
+    pub(crate) fn check_poisoned(&self) -> Result<(), Error> {
+        match &self.poisoned {
+            Some(report) => Err(Error::Poisoned(report.clone())),
+            None => Ok(()),
         }
     }
+
+    /// True if a region acquired by the current thread keeps the read tail
+    /// from ever getting clear of `end`: a write it reserved, a region one of
+    /// its receivers holds or a record one of its group members claimed.
+    pub(crate) fn held_up_by_current_thread(&self, end: &EndCursor) -> bool {
+        let me = sync::current().id();
+        let writes = self.reserved_by.iter();
+        let reads = self
+            .receivers
+            .values()
+            .flat_map(|r| r.held.iter().map(|(beg, by)| (beg, by)));
+        let claims = self.groups.values().flat_map(|g| g.claimed.iter());
+        writes
+            .chain(reads)
+            .chain(claims)
+            .any(|(beg, by)| *by == me && collide(end, beg))
+    }
+
+    /// Bytes between the read tail and the write head.
+    pub(crate) fn occupancy(&self) -> Option<u64> {
+        Some(self.position(self.writes.end)? - self.position(self.reads.beg.into())?)
+    }
+}
+
+/// Maps `nbytes` of the file `fd` shared between processes.
+#[cfg(target_os = "linux")]
+pub(crate) fn map_shared(fd: RawFd, nbytes: usize, prot: i32) -> io::Result<NonNull<u8>> {
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            nbytes,
+            prot,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(NonNull::new(ptr as *mut u8).unwrap())
 }
 
 impl Drop for RawChannel {
     fn drop(&mut self) {
         if self.capacity > 0 {
-            unsafe {
-                let layout = Layout::from_size_align_unchecked(self.capacity, 1 << 12);
-                alloc::dealloc(self.ptr.as_ptr(), layout)
+            match self.backing {
+                Backing::Heap => unsafe {
+                    let layout = Layout::from_size_align_unchecked(self.capacity, ALIGNMENT);
+                    alloc::dealloc(self.ptr.as_ptr(), layout)
+                },
+                #[cfg(target_os = "linux")]
+                Backing::Memfd(_) | Backing::Mapped => unsafe {
+                    libc::munmap(self.ptr.as_ptr() as *mut _, self.capacity);
+                },
             }
         }
     }
@@ -83,33 +547,242 @@ impl Drop for RawChannel {
 
 pub struct Channel {
     pub(crate) inner: Mutex<RawChannel>,
-    pub(crate) space_available: Condvar,
+    pub(crate) data_available: Condvar,
+    /// The buffer, which stays put for the channel's lifetime.
+    ptr: NonNull<u8>,
+    /// Whether commits store a CRC32C of each record. Senders compute it
+    /// before they take the lock.
+    pub(crate) checksums: AtomicBool,
 }
 
+// SAFETY: `ptr` is a copy of the buffer pointer `inner` owns, so it lives as
+// long as the channel. Bytes are only reached through it for intervals a
+// region holds, which the lock hands out without overlap.
+unsafe impl Send for Channel {}
+unsafe impl Sync for Channel {}
+
 impl Channel {
     pub fn new(nbytes: usize) -> Self {
+        Self::from_raw(RawChannel::new(nbytes))
+    }
+
+    fn from_raw(raw: RawChannel) -> Self {
         Channel {
-            inner: Mutex::new(RawChannel::new(nbytes)),
-            space_available: Condvar::new(),
+            ptr: raw.ptr,
+            inner: Mutex::new(raw),
+            data_available: Condvar::new(),
+            checksums: AtomicBool::new(false),
         }
     }
 
+    /// Creates a [`ConsumerGroup`] reading from the channel.
+    ///
+    /// # Panics
+    ///
+    /// If the channel has an exclusive receiver.
+    pub fn group(self: &Arc<Self>) -> ConsumerGroup {
+        ConsumerGroup::new(self.clone())
+    }
+
+    /// Like [`group`](Self::group), but returns None if the channel has an
+    /// exclusive receiver.
+    pub fn try_group(self: &Arc<Self>) -> Option<ConsumerGroup> {
+        ConsumerGroup::try_new(self.clone())
+    }
+
+    /// Like [`ChannelFactory::receiver`], but returns None if the channel
+    /// has an exclusive receiver.
+    pub fn try_receiver(self: &Arc<Self>) -> Option<Receiver> {
+        Receiver::try_new(self.clone())
+    }
+
     pub fn close(&self) {
         let mut ch = self.inner.lock();
         ch.is_accepting_writes = false;
-        self.space_available.notify_all();
+        ch.wake_writers();
+        self.data_available.notify_all();
+        ch.wake_watchers();
+    }
+
+    /// Creates a channel whose buffer lives in a memfd, so that it can be
+    /// mapped by other processes.
+    ///
+    /// See [`crate::io::shm`] for handing it out.
+    #[cfg(target_os = "linux")]
+    pub fn memfd(nbytes: usize) -> io::Result<Self> {
+        Ok(Self::from_raw(RawChannel::memfd(nbytes)?))
+    }
+
+    /// A channel over `nbytes` of shared memory mapped at `ptr`, which it
+    /// unmaps when dropped. Its stream starts at offset `start`.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn mapped(ptr: NonNull<u8>, nbytes: usize, start: usize) -> Self {
+        Self::from_raw(RawChannel::mapped(ptr, nbytes, start))
+    }
+
+    /// Registers `signal` to be notified whenever anything changes on the
+    /// channel.
+    pub(crate) fn watch(&self, signal: Arc<Signal>) {
+        self.inner.lock().watchers.push(signal);
+    }
+
+    pub(crate) fn unwatch(&self, signal: &Arc<Signal>) {
+        let mut ch = self.inner.lock();
+        if let Some(i) = ch.watchers.iter().position(|w| Arc::ptr_eq(w, signal)) {
+            ch.watchers.swap_remove(i);
+        }
+    }
+
+    /// Where a region starting at `beg` starts in the stream. For regions
+    /// that are held, which keeps their cycle known.
+    pub(crate) fn held_position(&self, beg: BegCursor) -> Position {
+        let position = self.inner.lock().to_position(beg);
+        position.expect("a held region's cycle is known")
+    }
+
+    /// The read tail: every receiver has released the bytes before it.
+    pub(crate) fn released(&self) -> Option<Position> {
+        let ch = self.inner.lock();
+        ch.to_position(ch.reads.beg)
+    }
+
+    pub(crate) fn has_receivers(&self) -> bool {
+        !self.inner.lock().receivers.is_empty()
+    }
+
+    /// The memfd holding the channel's buffer, if it has one.
+    #[cfg(target_os = "linux")]
+    pub fn memfd_raw_fd(&self) -> Option<RawFd> {
+        match &self.inner.lock().backing {
+            Backing::Memfd(fd) => Some(fd.as_raw_fd()),
+            Backing::Heap | Backing::Mapped => None,
+        }
+    }
+
+    /// The current state of the channel.
+    ///
+    /// # Panics
+    ///
+    /// If the channel lost track of where a cursor is in the stream, which
+    /// [`validate`](Self::validate) reports as [`Invariant::KnownCycles`].
+    pub fn snapshot(&self) -> Snapshot {
+        let snapshot = self.inner.lock().snapshot();
+        snapshot.expect("every cursor has a position")
+    }
+
+    /// A copy of the channel's counters.
+    ///
+    /// # Panics
+    ///
+    /// Like [`snapshot`](Self::snapshot).
+    pub fn stats(&self) -> Stats {
+        let stats = self.inner.lock().stats();
+        stats.expect("every cursor has a position")
+    }
+
+    /// The size of the channel's buffer in bytes.
+    pub fn capacity(&self) -> usize {
+        self.inner.lock().capacity
+    }
+
+    /// Checks the channel's invariants and reports the ones that don't hold.
+    ///
+    /// A violation poisons the channel, like one found while it's in use.
+    pub fn validate(&self) -> Report {
+        let mut ch = self.inner.lock();
+        let report = Report {
+            violations: ch.violations(),
+            state: ch.to_string(),
+        };
+        if !report.is_ok() {
+            self.poison(&mut ch, report.clone());
+        }
+        report
+    }
+
+    /// Marks the channel as broken and wakes everybody waiting on it.
+    /// From then on calls fail with the returned [`Error::Poisoned`].
+    ///
+    /// The first report sticks if the channel is already poisoned.
+    pub(crate) fn poison(&self, ch: &mut RawChannel, report: Report) -> Error {
+        if ch.poisoned.is_none() {
+            warn!("poisoned: {}", report);
+            ch.poisoned = Some(Arc::new(report));
+            ch.is_accepting_writes = false;
+            ch.wake_writers();
+            self.data_available.notify_all();
+            ch.wake_watchers();
+        }
+        Error::Poisoned(ch.poisoned.clone().unwrap())
+    }
+
+    /// Poisons the channel if `result` is a violation.
+    pub(crate) fn or_poison<T>(
+        &self,
+        ch: &mut RawChannel,
+        result: Result<T, Violation>,
+    ) -> Result<T, Error> {
+        result.map_err(|violation| {
+            let report = Report {
+                violations: vec![violation],
+                state: ch.to_string(),
+            };
+            self.poison(ch, report)
+        })
+    }
+
+    /// Whether a sender panicking while it holds a region poisons the
+    /// channel, so receivers find out a producer died part way through a
+    /// record. Off by default, in which case the region is just discarded
+    /// or marked corrupt. See [`Record::is_corrupt`](super::Record::is_corrupt).
+    ///
+    /// Receivers that read bytes rather than records never see that mark, so
+    /// they need this on to find out.
+    pub fn set_poison_on_panic(&self, poison: bool) {
+        self.inner.lock().poison_on_panic = poison;
+    }
+
+    /// Whether a CRC32C of each region is computed when it's committed, so
+    /// readers can check the bytes weren't changed since with
+    /// [`Record::verify`](super::Record::verify). Off by default.
+    ///
+    /// Only regions committed after this is turned on get a checksum.
+    pub fn set_checksums(&self, enabled: bool) {
+        self.checksums.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Sets how senders and receivers wait when they block. Calls that are
+    /// already blocked keep waiting the way they started.
+    pub fn set_wait_strategy(&self, strategy: WaitStrategy) {
+        self.inner.lock().wait_strategy = strategy;
+    }
+
+    pub fn wait_strategy(&self) -> WaitStrategy {
+        self.inner.lock().wait_strategy
     }
 
     // Base pointer for the region controlled by the channel.
     // Used for debugging. Might not be desirable otherwise.
     pub fn as_ptr(&self) -> *const u8 {
-        self.inner.lock().ptr.as_ptr()
+        self.ptr.as_ptr()
     }
 }
 
 pub trait ChannelFactory {
     fn sender(&self) -> Sender;
+    /// # Panics
+    ///
+    /// If the channel has an exclusive receiver.
     fn receiver(&self) -> Receiver;
+
+    /// Creates the channel's only receiver, which may modify the bytes it
+    /// reads with [`Receiver::next_mut`].
+    ///
+    /// Returns None if the channel already has receivers or consumer groups.
+    /// While it exists no others can be created, except receivers that
+    /// depend on it (see [`Receiver::dependent`]): they only see bytes after
+    /// it is done with them.
+    fn exclusive_receiver(&self) -> Option<Receiver>;
 }
 
 impl ChannelFactory for Arc<Channel> {
@@ -120,6 +793,10 @@ impl ChannelFactory for Arc<Channel> {
     fn receiver(&self) -> Receiver {
         Receiver::new(self.clone())
     }
+
+    fn exclusive_receiver(&self) -> Option<Receiver> {
+        Receiver::exclusive(self.clone())
+    }
 }
 
 pub fn channel(nbytes: usize) -> (Sender, Receiver) {
@@ -129,7 +806,7 @@ pub fn channel(nbytes: usize) -> (Sender, Receiver) {
 
 #[cfg(test)]
 mod tests {
-    use crate::base::channel::Counter;
+    use crate::base::{channel, channel::Counter, ChannelFactory, ReceiverState};
 
     #[test]
     fn counter_insert() {
@@ -153,4 +830,97 @@ mod tests {
         c.remove(&5);
         assert_eq!(c.min(), Some(&6));
     }
+
+    #[test]
+    fn stats_track_occupancy_wraps_and_lag() {
+        let (mut tx, mut rx) = channel(10);
+        let slow = tx.channel().receiver();
+
+        tx.map(6).unwrap().fill(0);
+        let stats = tx.channel().stats();
+        assert_eq!(stats.committed, 6);
+        assert_eq!(stats.occupancy, 6);
+        assert_eq!(
+            stats.receivers.iter().map(|r| r.lag).collect::<Vec<_>>(),
+            [6, 6]
+        );
+
+        rx.next().unwrap();
+        drop(slow);
+        tx.map(3).unwrap().fill(1);
+        tx.map(4).unwrap().fill(2); // wraps, skipping the last byte
+        let stats = tx.channel().stats();
+        assert_eq!(stats.committed, 13);
+        assert_eq!(stats.released, 6);
+        assert_eq!(stats.occupancy, 7);
+        assert_eq!(stats.peak_occupancy, 7);
+        assert_eq!(stats.wraps, 1);
+        assert_eq!(stats.writer_waits, 0);
+        assert_eq!(stats.receivers.len(), 1);
+        assert_eq!(stats.receivers[0].id, rx.id());
+        assert_eq!(stats.receivers[0].lag, 7);
+
+        while rx.next().is_some() {}
+        let stats = tx.channel().stats();
+        assert_eq!(stats.released, 13);
+        assert_eq!(stats.occupancy, 0);
+        assert_eq!(stats.receivers[0].lag, 0);
+    }
+
+    #[test]
+    fn snapshot_reports_positions_and_receiver_states() {
+        let (mut tx, mut rx) = channel(10);
+        let ch = tx.channel().clone();
+        tx.map(6).unwrap().fill(0);
+        let _ = rx.next().unwrap();
+        let pending = tx.map(3).unwrap();
+
+        let snapshot = ch.snapshot();
+        assert_eq!(snapshot.capacity, 10);
+        assert!(!snapshot.closed);
+        assert_eq!(snapshot.reads, 6..6);
+        assert_eq!(snapshot.writes, 6..9);
+        assert_eq!(snapshot.outstanding_writes, vec![(6..9)]);
+        assert_eq!(snapshot.free(), 7);
+        drop(pending);
+
+        // Wrap, skipping the last byte of the buffer.
+        tx.map(4).unwrap();
+        let region = rx.next().unwrap();
+        let snapshot = ch.snapshot();
+        assert_eq!(snapshot.reads, 6..13);
+        assert_eq!(snapshot.receivers.len(), 1);
+        assert_eq!(snapshot.receivers[0].position, 9);
+        assert_eq!(snapshot.receivers[0].state, ReceiverState::Reading(1));
+        // Only the bytes between the write head and the read tail are free,
+        // not the one skipped.
+        assert_eq!(snapshot.skipped, 1);
+        assert_eq!(snapshot.free(), 2);
+        drop(region);
+
+        ch.close();
+        while rx.next().is_some() {}
+        let snapshot = ch.snapshot();
+        assert!(snapshot.closed);
+        assert_eq!(snapshot.reads, 13..13);
+        assert_eq!(snapshot.receivers[0].state, ReceiverState::Idle);
+    }
+
+    #[test]
+    fn stats_count_writer_waits() {
+        let (mut tx, mut rx) = channel(10);
+        tx.map(10).unwrap();
+        let reader = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            rx.next().unwrap();
+            rx
+        });
+        tx.map(5).unwrap();
+        reader.join().unwrap();
+
+        let stats = tx.channel().stats();
+        assert_eq!(stats.writer_waits, 1);
+        assert!(stats.writer_wait_time >= std::time::Duration::from_millis(10));
+        assert_eq!(stats.peak_occupancy, 10);
+    }
 }
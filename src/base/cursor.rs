@@ -1,4 +1,52 @@
-use std::{collections::btree_set::Intersection, fmt::Display};
+use std::{
+    collections::btree_set::Intersection,
+    fmt::Display,
+    ops::{Add, Sub},
+};
+
+/// A place in a channel's stream: the number of bytes committed before it
+/// since the channel was created.
+///
+/// Unlike the offsets into the buffer, positions only ever increase. Space
+/// skipped at the end of the buffer when a region wraps isn't counted, so
+/// consecutive regions have consecutive positions.
+#[derive(Default, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub struct Position(pub u64);
+
+impl Position {
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<Position> for u64 {
+    fn from(p: Position) -> Self {
+        p.0
+    }
+}
+
+impl Add<u64> for Position {
+    type Output = Position;
+
+    fn add(self, nbytes: u64) -> Self::Output {
+        Position(self.0 + nbytes)
+    }
+}
+
+/// The number of bytes between two positions.
+impl Sub for Position {
+    type Output = u64;
+
+    fn sub(self, other: Position) -> Self::Output {
+        self.0 - other.0
+    }
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 #[derive(Default, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
 pub(crate) struct BegCursor {
@@ -6,7 +54,7 @@ pub(crate) struct BegCursor {
     pub(crate) offset: isize,
 }
 
-#[derive(Default, Debug, Clone, Copy, Hash)]
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub(crate) struct EndCursor {
     pub(crate) cycle: isize,
     pub(crate) offset: isize,
@@ -37,17 +85,15 @@ impl Interval {
 
 impl PartialOrd for Interval {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match self.beg.partial_cmp(&other.beg) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        self.end.partial_cmp(&other.end)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for Interval {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        self.beg
+            .cmp(&other.beg)
+            .then_with(|| self.end.cmp(&other.end))
     }
 }
 
@@ -58,7 +104,7 @@ impl Display for Interval {
             "{beg}-{end} high:{high}",
             beg = self.beg,
             end = self.end,
-            high = if let Some(h) = self.high_mark { h } else { -1 }
+            high = self.high_mark.unwrap_or(-1)
         )
     }
 }
@@ -78,7 +124,7 @@ impl BegCursor {
             == 0
     }
 
-    pub(crate) fn to_end(&self, high_mark: Option<isize>) -> EndCursor {
+    pub(crate) fn to_end(self, high_mark: Option<isize>) -> EndCursor {
         if let Some(high_mark) = high_mark {
             if self.offset == 0 {
                 return EndCursor {
@@ -135,7 +181,35 @@ impl EndCursor {
         }
     }
 
-    pub(crate) fn to_beg(&self, high_mark: Option<isize>) -> BegCursor {
+    /// Like `next_region`, but for a region that must start at `offset`:
+    /// either right here, or at the start of the buffer after wrapping.
+    ///
+    /// Returns None if the region can't start at `offset`.
+    pub(crate) fn region_at(
+        &self,
+        offset: usize,
+        amount: usize,
+        capacity: usize,
+    ) -> Option<Interval> {
+        let offset = offset as isize;
+        if offset == self.offset && offset + amount as isize <= capacity as isize {
+            Some(self.next_region(amount, capacity))
+        } else if offset == 0 && amount <= capacity {
+            let cycle = self.cycle + 1;
+            Some(Interval {
+                beg: BegCursor { offset: 0, cycle },
+                end: EndCursor {
+                    offset: amount as isize,
+                    cycle,
+                },
+                high_mark: Some(self.offset),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn to_beg(self, high_mark: Option<isize>) -> BegCursor {
         if let Some(high_mark) = high_mark {
             if high_mark == self.offset {
                 return BegCursor {
@@ -184,27 +258,17 @@ impl From<&EndCursor> for BegCursor {
     }
 }
 
-impl PartialEq for EndCursor {
-    fn eq(&self, other: &Self) -> bool {
-        self.cycle == other.cycle && self.offset == other.offset
-    }
-}
-
-impl Eq for EndCursor {}
-
 impl PartialOrd for EndCursor {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match self.cycle.partial_cmp(&other.cycle) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        self.offset.partial_cmp(&other.offset)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for EndCursor {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        self.cycle
+            .cmp(&other.cycle)
+            .then_with(|| self.offset.cmp(&other.offset))
     }
 }
 
@@ -0,0 +1,42 @@
+//! Errors returned by channel operations.
+
+use std::{fmt::Display, sync::Arc};
+
+use super::{cursor::Position, validate::Report};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The channel stopped accepting writes.
+    Closed,
+    /// The request can never fit in the channel's capacity.
+    TooLarge,
+    /// The request would block forever, because only regions held by the
+    /// calling thread stand in its way.
+    WouldDeadlock,
+    /// The channel found its state broken and stopped working. Carries what
+    /// was wrong and a dump of the state.
+    ///
+    /// Calls that return an `Option` return None instead.
+    Poisoned(Arc<Report>),
+    /// A record's bytes don't match the checksum taken when it was
+    /// committed. See [`Channel::set_checksums`](super::Channel::set_checksums).
+    Corrupt { position: Position },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Closed => write!(f, "channel is closed"),
+            Error::TooLarge => write!(f, "request exceeds the channel's capacity"),
+            Error::WouldDeadlock => {
+                write!(f, "request waits on regions held by the calling thread")
+            }
+            Error::Poisoned(report) => write!(f, "channel is poisoned:\n{}", report),
+            Error::Corrupt { position } => {
+                write!(f, "record at {} doesn't match its checksum", position.get())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,373 @@
+//! Receivers that share the work of reading a channel.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Deref,
+    sync::Arc,
+    time::Instant,
+};
+
+use super::{
+    channel::{Channel, RawChannel},
+    cursor::{BegCursor, EndCursor, Interval, Position},
+    error::Error,
+    receiver::Receiver,
+    record::{verify, Meta, RecordEntry},
+    sync::{self, ThreadId},
+    validate::Violation,
+    wait::Backoff,
+};
+
+/// A set of members that split a channel's records between them.
+///
+/// Every record goes to exactly one member, in contrast to [`Receiver`]s,
+/// which each see every byte. To writers the group looks like a single
+/// receiver: space is only given back once all the records before it have
+/// been released, even when members finish out of order.
+///
+/// Groups and ordinary receivers can share a channel. Like a receiver, a
+/// group starts reading at the channel's read tail when it's created.
+pub struct ConsumerGroup {
+    inner: Arc<GroupInner>,
+}
+
+/// One member of a [`ConsumerGroup`].
+pub struct GroupReceiver {
+    inner: Arc<GroupInner>,
+}
+
+struct GroupInner {
+    channel: Arc<Channel>,
+    id: u64,
+}
+
+/// The channel's view of a group.
+#[derive(Debug)]
+pub(crate) struct GroupEntry {
+    /// Where the next record will be claimed.
+    pub(crate) next: EndCursor,
+    /// Everything before this has been released. Counts as one of the
+    /// channel's outstanding reads.
+    pub(crate) floor: BegCursor,
+    /// Records released ahead of `floor`, keyed by their start position.
+    done: BTreeMap<u64, EndCursor>,
+    /// Where the records claimed but not yet released start, and the thread
+    /// that claimed each.
+    pub(crate) claimed: HashMap<BegCursor, ThreadId>,
+}
+
+impl ConsumerGroup {
+    pub(crate) fn new(channel: Arc<Channel>) -> Self {
+        Self::try_new(channel).expect("channel has an exclusive receiver")
+    }
+
+    /// Like `new`, but returns None if the channel has an exclusive receiver.
+    pub(crate) fn try_new(channel: Arc<Channel>) -> Option<Self> {
+        let id = {
+            let mut ch = channel.inner.lock();
+            if ch.exclusive.is_some() {
+                return None;
+            }
+            let floor = ch.reads.beg;
+            ch.outstanding_reads.insert(floor);
+            let id = ch.next_group_id;
+            ch.next_group_id += 1;
+            ch.groups.insert(
+                id,
+                GroupEntry {
+                    next: floor.to_end(None),
+                    floor,
+                    done: BTreeMap::new(),
+                    claimed: HashMap::new(),
+                },
+            );
+            id
+        };
+        Some(ConsumerGroup {
+            inner: Arc::new(GroupInner { channel, id }),
+        })
+    }
+
+    /// Adds a member to the group.
+    pub fn member(&self) -> GroupReceiver {
+        GroupReceiver {
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn channel(&self) -> &Arc<Channel> {
+        &self.inner.channel
+    }
+
+    /// Identifies this group in the channel's [`Stats`](super::Stats).
+    pub fn id(&self) -> u64 {
+        self.inner.id
+    }
+}
+
+impl GroupReceiver {
+    pub fn channel(&self) -> &Arc<Channel> {
+        &self.inner.channel
+    }
+
+    /// Claims the next unclaimed record, or returns None if there isn't one
+    /// right now.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<GroupRecord<'_>> {
+        let claimed = {
+            let channel = &self.inner.channel;
+            let mut ch = channel.inner.lock();
+            ch.check_poisoned().ok()?;
+            let claimed = Self::claim(&mut ch, self.inner.id);
+            channel.or_poison(&mut ch, claimed).ok()??
+        };
+        Some(self.record(claimed))
+    }
+
+    /// Claims the next unclaimed record, blocking until there is one.
+    ///
+    /// Returns None once the channel is closed and every record has been
+    /// claimed.
+    pub fn recv(&mut self) -> Option<GroupRecord<'_>> {
+        let claimed = {
+            let channel = &self.inner.channel;
+            let mut ch = channel.inner.lock();
+            let mut backoff = Backoff::new(&ch);
+            loop {
+                ch.check_poisoned().ok()?;
+                let claimed = Self::claim(&mut ch, self.inner.id);
+                if let Some(claimed) = channel.or_poison(&mut ch, claimed).ok()? {
+                    break claimed;
+                }
+                if !ch.is_accepting_writes && ch.outstanding_writes.is_empty() {
+                    return None;
+                }
+                backoff.wait(&mut ch, &channel.data_available);
+            }
+        };
+        Some(self.record(claimed))
+    }
+
+    fn claim(
+        ch: &mut RawChannel,
+        id: u64,
+    ) -> Result<Option<(Interval, *const u8, RecordEntry)>, Violation> {
+        let next = ch.groups[&id].next;
+        let (interval, record) = match Receiver::record_interval(ch, next, ch.reads.end)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let group = ch.groups.get_mut(&id).unwrap();
+        group.next = interval.end;
+        group.claimed.insert(interval.beg, sync::current().id());
+        let ptr = unsafe { ch.ptr.as_ptr().offset(interval.beg.offset) as *const u8 };
+        Ok(Some((interval, ptr, record)))
+    }
+
+    fn record(&mut self, (cur, ptr, entry): (Interval, *const u8, RecordEntry)) -> GroupRecord<'_> {
+        GroupRecord {
+            owner: self,
+            cur,
+            buf: unsafe { std::slice::from_raw_parts(ptr, cur.len() as usize) },
+            seq: entry.seq,
+            meta: entry.meta,
+            committed: entry.committed,
+            corrupt: entry.corrupt,
+            crc: entry.crc,
+        }
+    }
+
+    fn release(&self, interval: &Interval) {
+        let channel = &self.inner.channel;
+        let mut ch = channel.inner.lock();
+        let mut group = ch.groups.remove(&self.inner.id).unwrap();
+        group.claimed.remove(&interval.beg);
+        let beg = ch.position(interval.beg.into());
+        if beg.is_some() && beg == ch.position(group.floor.into()) {
+            // Move the floor past this record and any released after it.
+            let mut to = interval.end;
+            while let Some(end) = ch.position(to).and_then(|p| group.done.remove(&p)) {
+                to = end;
+            }
+            Receiver::release(&mut ch, group.floor, to.into());
+            group.floor = to.into();
+            ch.wake_writers();
+            ch.wake_watchers();
+        } else if let Some(beg) = beg {
+            group.done.insert(beg, interval.end);
+        }
+        ch.groups.insert(self.inner.id, group);
+    }
+}
+
+impl Drop for GroupInner {
+    fn drop(&mut self) {
+        let mut ch = self.channel.inner.lock();
+        let group = ch.groups.remove(&self.id).unwrap();
+        Receiver::detach(&mut ch, group.floor);
+        ch.wake_writers();
+        ch.wake_watchers();
+    }
+}
+
+/// A record claimed by a [`GroupReceiver`]. Released when dropped.
+pub struct GroupRecord<'a> {
+    owner: &'a mut GroupReceiver,
+    cur: Interval,
+    buf: &'a [u8],
+    seq: u64,
+    meta: Meta,
+    committed: Instant,
+    corrupt: bool,
+    crc: Option<u32>,
+}
+
+impl<'a> GroupRecord<'a> {
+    pub fn meta(&self) -> &Meta {
+        &self.meta
+    }
+
+    /// See [`Record::seq`](super::Record::seq).
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// See [`Record::committed`](super::Record::committed).
+    pub fn committed(&self) -> Instant {
+        self.committed
+    }
+
+    /// See [`Record::is_corrupt`](super::Record::is_corrupt).
+    pub fn is_corrupt(&self) -> bool {
+        self.corrupt
+    }
+
+    /// Where the record starts in the channel's stream.
+    pub fn position(&self) -> Position {
+        self.owner.inner.channel.held_position(self.cur.beg)
+    }
+
+    /// See [`Record::checksum`](super::Record::checksum).
+    pub fn checksum(&self) -> Option<u32> {
+        self.crc
+    }
+
+    /// See [`Record::verify`](super::Record::verify).
+    pub fn verify(&self) -> Result<(), Error> {
+        verify(self.buf, self.crc, || self.position())
+    }
+}
+
+impl<'a> Deref for GroupRecord<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.buf
+    }
+}
+
+impl<'a> AsRef<[u8]> for GroupRecord<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.buf
+    }
+}
+
+impl<'a> Drop for GroupRecord<'a> {
+    fn drop(&mut self) {
+        self.owner.release(&self.cur);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread::spawn};
+
+    use crate::base::{Channel, ChannelFactory, GroupSnapshot, GroupStats};
+
+    #[test]
+    fn out_of_order_release_holds_back_writers() {
+        let ch = Arc::new(Channel::new(10));
+        let mut tx = ch.sender();
+        let group = ch.group();
+        let (mut a, mut b, mut c) = (group.member(), group.member(), group.member());
+
+        tx.map(4).unwrap().fill(1);
+        tx.map(4).unwrap().fill(2);
+        let first = a.next().unwrap();
+        let second = b.next().unwrap();
+        assert_eq!((&*first, first.seq()), (&[1u8; 4][..], 0));
+        assert_eq!((&*second, second.seq()), (&[2u8; 4][..], 1));
+        assert!(c.next().is_none());
+
+        drop(second);
+        assert_eq!(ch.snapshot().reads.start, 0);
+        drop(first);
+        assert_eq!(ch.snapshot().reads.start, 8);
+    }
+
+    #[test]
+    fn stats_and_snapshot_show_what_a_group_has_left() {
+        let ch = Arc::new(Channel::new(10));
+        let mut tx = ch.sender();
+        let group = ch.group();
+        let mut member = group.member();
+
+        tx.map(4).unwrap().fill(1);
+        tx.map(3).unwrap().fill(2);
+        let first = member.next().unwrap();
+        let id = group.id();
+        assert_eq!(ch.stats().groups, [GroupStats { id, lag: 3 }]);
+        let groups = ch.snapshot().groups;
+        assert_eq!(groups, [GroupSnapshot { id, released: 0, claimed: 4 }]);
+
+        drop(first);
+        assert_eq!(ch.snapshot().groups[0].released, 4);
+    }
+
+    #[test]
+    fn each_record_goes_to_one_member() {
+        const COUNT: usize = 1000;
+        let ch = Arc::new(Channel::new(64));
+        let group = ch.group();
+        let mut broadcast = ch.receiver();
+
+        let members: Vec<_> = (0..3)
+            .map(|_| {
+                let mut member = group.member();
+                spawn(move || {
+                    let mut seen = Vec::new();
+                    while let Some(record) = member.recv() {
+                        assert!(record.iter().all(|&b| b == record[0]));
+                        seen.push(record.seq());
+                    }
+                    seen
+                })
+            })
+            .collect();
+        drop(group);
+
+        let writer = {
+            let mut tx = ch.sender();
+            spawn(move || {
+                for i in 0..COUNT {
+                    tx.map(1 + i % 7).unwrap().fill(i as u8);
+                }
+                tx.channel().close();
+            })
+        };
+
+        let mut bytes = 0;
+        while let Some(region) = broadcast.recv() {
+            bytes += region.len();
+        }
+        writer.join().unwrap();
+
+        let mut seen: Vec<u64> = members
+            .into_iter()
+            .flat_map(|m| m.join().unwrap())
+            .collect();
+        seen.sort();
+        assert_eq!(seen, (0..COUNT as u64).collect::<Vec<_>>());
+        assert_eq!(bytes, (0..COUNT).map(|i| 1 + i % 7).sum::<usize>());
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     collections::{btree_set::Intersection, HashSet},
+    io::{self, IoSlice, Write},
     ops::Deref,
     sync::{mpsc::channel, Arc},
 };
@@ -10,17 +11,26 @@ use parking_lot::{RwLock, RwLockUpgradableReadGuard};
 use crate::base::cursor::EndCursor;
 
 use super::{
-    channel::{Channel, RawChannel},
+    channel::{Channel, RawChannel, ReceiverEntry},
+    error::Error,
     cursor::{BegCursor, Interval},
-    region::Region,
+    record::{Record, RecordEntry},
+    region::{Region, RegionMut},
+    sync::{self, MutexGuard},
+    validate::{Invariant, Violation},
+    wait::Backoff,
 };
 
 pub struct Receiver {
     channel: Arc<Channel>,
+    id: u64,
 
     /// The read position
     /// This is often the beginning of the next read region.
     cur: EndCursor,
+
+    /// True if this is the channel's exclusive receiver.
+    exclusive: bool,
 }
 
 unsafe impl Send for Receiver {}
@@ -28,140 +38,970 @@ unsafe impl Sync for Receiver {}
 
 impl Receiver {
     pub(crate) fn new(channel: Arc<Channel>) -> Self {
-        let cur = {
+        Self::try_new(channel).expect("channel has an exclusive receiver")
+    }
+
+    /// Like `new`, but returns None if the channel has an exclusive receiver.
+    pub(crate) fn try_new(channel: Arc<Channel>) -> Option<Self> {
+        let (id, cur) = {
+            let mut ch = channel.inner.lock();
+            if ch.exclusive.is_some() {
+                return None;
+            }
+            Self::register(&mut ch, None)
+        };
+        Some(Receiver {
+            channel,
+            id,
+            cur,
+            exclusive: false,
+        })
+    }
+
+    pub(crate) fn exclusive(channel: Arc<Channel>) -> Option<Self> {
+        let (id, cur) = {
             let mut ch = channel.inner.lock();
-            let cur = ch.reads.beg.to_end(None);
-            ch.outstanding_reads.insert(cur.into());
-            cur
+            if !ch.receivers.is_empty() || !ch.groups.is_empty() {
+                return None;
+            }
+            let (id, cur) = Self::register(&mut ch, None);
+            ch.exclusive = Some(id);
+            (id, cur)
+        };
+        Some(Receiver {
+            channel,
+            id,
+            cur,
+            exclusive: true,
+        })
+    }
+
+    /// Adds a receiver to the channel's registry and returns its id and
+    /// starting position.
+    fn register(ch: &mut RawChannel, upstream: Option<u64>) -> (u64, EndCursor) {
+        let cur = match upstream {
+            Some(up) => EndCursor::from(Self::floor(&ch.receivers[&up])),
+            None => ch.reads.beg.to_end(None),
         };
-        Receiver { channel, cur }
+        ch.outstanding_reads.insert(cur.into());
+        let id = ch.next_receiver_id;
+        ch.next_receiver_id += 1;
+        ch.receivers.insert(
+            id,
+            ReceiverEntry {
+                cur,
+                held: Vec::new(),
+                upstream,
+                waiting: false,
+            },
+        );
+        (id, cur)
+    }
+
+    /// Creates a receiver that only sees bytes after this one has released
+    /// them.
+    ///
+    /// This lets a pipeline of stages work in place on one channel: e.g. a
+    /// stage that corrects each frame, followed by one that writes it to
+    /// disk. Writers are held back by the last stage. The new receiver
+    /// starts where this one has released up to.
+    ///
+    /// If this receiver is dropped, receivers depending on it wait on its
+    /// own upstream instead, or on the writers if it had none.
+    pub fn dependent(&self) -> Receiver {
+        let (id, cur) = Self::register(&mut self.channel.inner.lock(), Some(self.id));
+        Receiver {
+            channel: self.channel.clone(),
+            id,
+            cur,
+            exclusive: false,
+        }
+    }
+
+    /// Identifies this receiver in the channel's [`Stats`](super::Stats).
+    pub fn id(&self) -> u64 {
+        self.id
     }
 
     pub fn channel(&self) -> &Arc<Channel> {
         &self.channel
     }
 
+    /// Where in the buffer the next region this receiver reads starts,
+    /// unless the stream wraps first.
+    pub(crate) fn offset(&self) -> usize {
+        self.cur.offset as usize
+    }
+
     pub fn is_open(&self) -> bool {
         let ch = self.channel.inner.lock();
-        ch.is_accepting_writes || self.cur != ch.reads.end
+        ch.is_accepting_writes || self.cur != Self::visible_end(&ch, self.id)
     }
 
-    pub fn next(&mut self) -> Option<Region> {
+    /// Returns the next readable region, or None if nothing is available
+    /// right now.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Region<'_>> {
+        self.try_next().ok().flatten()
+    }
+
+    /// Like `next`, but fails if the channel is poisoned.
+    pub fn try_next(&mut self) -> Result<Option<Region<'_>>, Error> {
         let (interval, ptr) = {
             let mut ch = self.channel.inner.lock();
+            ch.check_poisoned()?;
+            let end = Self::visible_end(&ch, self.id);
+            let acquired = Self::acquire(&mut ch, &mut self.cur, end);
+            match self.channel.or_poison(&mut ch, acquired)? {
+                Some(acquired) => {
+                    Self::acquired(&mut ch, self.id, &acquired.0);
+                    acquired
+                }
+                None => return Ok(None),
+            }
+        };
+        Ok(Some(self.region(interval, ptr)))
+    }
 
-            // FIXME: Got
-            // 'R1' panicked at 'cur:61441(11048) reads:61441(11048)-4895(11049) high:-1'
-            // 'R1' panicked at 'cur:61440(10762) reads:61440(10762)-45073(10763) high:-1'
-            //
-            // Shouldn't high mark be set here. I'm inclined to think this is mostly a fine state
-            // but that high mark should still be set.
-            assert!(
-                (ch.reads.high_mark.is_some() && ch.reads.end.cycle == ch.reads.beg.cycle + 1)
-                    || (ch.reads.high_mark.is_none() && ch.reads.end.cycle == ch.reads.beg.cycle),
-                "cur:{} reads:{} ch:{:?}",
-                self.cur,
-                ch.reads,
-                ch
-            );
-            assert!(
-                ch.reads.beg <= self.cur.into() && self.cur <= ch.reads.end,
-                "cur:{} reads:{} ch:{:?}",
-                self.cur,
-                ch.reads,
-                ch
-            );
+    /// Like `next` but the region can be modified in place, e.g. to swap
+    /// bytes before they're written to disk.
+    ///
+    /// # Panics
+    ///
+    /// If the receiver wasn't created with
+    /// [`exclusive_receiver`](super::ChannelFactory::exclusive_receiver).
+    pub fn next_mut(&mut self) -> Option<RegionMut<'_>> {
+        assert!(self.exclusive, "receiver isn't exclusive");
+        let (interval, ptr) = {
+            let mut ch = self.channel.inner.lock();
+            let end = Self::visible_end(&ch, self.id);
+            let acquired = Self::acquire(&mut ch, &mut self.cur, end);
+            let acquired = self.channel.or_poison(&mut ch, acquired).ok()??;
+            Self::acquired(&mut ch, self.id, &acquired.0);
+            acquired
+        };
+        Some(self.region_mut(interval, ptr as *mut u8))
+    }
 
-            // ^^^^ 
-            // thread 'R1' panicked at 'cur:61457(10752) reads:61457(10752)-4130(10753) high:-1 
-            // ch:RawChannel { 
-                // ptr: 0x150008000, capacity: 65536, is_accepting_writes: false, 
-                // writes: Interval { beg: BegCursor { cycle: 10753, offset: 4130 }, end: EndCursor { cycle: 10752, offset: 61457 }, high_mark: None }, 
-                // reads: Interval { beg: BegCursor { cycle: 10752, offset: 61457 }, end: EndCursor { cycle: 10753, offset: 4130 }, high_mark: None }, 
-                // outstanding_writes: {}, outstanding_reads: Counter { inner: {BegCursor { cycle: 10752, offset: 61457 }: 3} } }', 
-            // src/base/receiver.rs:59:13
-
-            // Only wrap if there's a cycle difference.
-            //
-            // This is particularly important for the case where `reads.beg`
-            // and `reads.end` are in different cycles, but the `self.cur` is
-            // at `reads.end` and that happens to correspond to the `high_mark`.
-            let beg = self.cur.to_beg(if self.cur.cycle == ch.reads.end.cycle {
-                None
-            } else {
-                ch.reads.high_mark
-            });
-
-            // Compute the interval to read
-            // It will never straddle the cycle boundary so the high_mark
-            // should never be set.
-            let interval = if beg.cycle == ch.reads.end.cycle {
-                Interval {
-                    beg,
-                    end: ch.reads.end,
-                    high_mark: None,
-                }
-            } else {
-                assert_eq!(ch.reads.beg.cycle, beg.cycle, "beg:{} ch:{}", beg, ch);
-                assert!(ch.reads.high_mark.is_some(), "beg:{} ch:{}", beg, ch);
-                let high_mark = ch.reads.high_mark.unwrap();
-                Interval {
-                    beg,
-                    end: EndCursor {
-                        cycle: ch.reads.beg.cycle,
-                        offset: high_mark,
-                    },
-                    high_mark: None,
+    /// Like `next_mut` but blocks until a region is available.
+    ///
+    /// Returns None once the channel is closed and drained.
+    pub fn recv_mut(&mut self) -> Option<RegionMut<'_>> {
+        assert!(self.exclusive, "receiver isn't exclusive");
+        let (interval, ptr) = self.recv_raw()?;
+        Some(self.region_mut(interval, ptr as *mut u8))
+    }
+
+    /// Returns the next record, or None if nothing is available right now.
+    ///
+    /// Unlike `next`, which returns everything readable at once, this stops
+    /// at the end of the region the sender mapped. If `next` left the
+    /// receiver part way through a region, the rest of it is returned.
+    pub fn next_record(&mut self) -> Option<Record<'_>> {
+        let acquired = {
+            let mut ch = self.channel.inner.lock();
+            ch.check_poisoned().ok()?;
+            let end = Self::visible_end(&ch, self.id);
+            let acquired = Self::acquire_record(&mut ch, &mut self.cur, end);
+            let acquired = self.channel.or_poison(&mut ch, acquired).ok()??;
+            Self::acquired(&mut ch, self.id, &acquired.0);
+            acquired
+        };
+        Some(self.record(acquired))
+    }
+
+    /// Like `next_record` but blocks until a record is available.
+    ///
+    /// Returns None once the channel is closed and drained.
+    pub fn recv_record(&mut self) -> Option<Record<'_>> {
+        self.try_recv_record_unverified().ok().flatten()
+    }
+
+    /// Like `recv_record`, but fails if the channel is poisoned, and with
+    /// [`Error::Corrupt`] if the record doesn't match its
+    /// [checksum](Record::verify). A record that fails is still consumed.
+    /// Returns `Ok(None)` once the channel is closed and drained.
+    pub fn try_recv_record(&mut self) -> Result<Option<Record<'_>>, Error> {
+        let Some(record) = self.try_recv_record_unverified()? else {
+            return Ok(None);
+        };
+        record.verify()?;
+        Ok(Some(record))
+    }
+
+    /// Like `try_recv_record`, but hands back records that don't match their
+    /// checksum too, e.g. to copy them as they are.
+    pub(crate) fn try_recv_record_unverified(&mut self) -> Result<Option<Record<'_>>, Error> {
+        let acquired = self.try_recv_record_raw()?;
+        Ok(acquired.map(|acquired| self.record(acquired)))
+    }
+
+    fn try_recv_record_raw(&mut self) -> Result<Option<(Interval, *const u8, RecordEntry)>, Error> {
+        let mut ch = self.channel.inner.lock();
+        let mut backoff = Backoff::new(&ch);
+        loop {
+            ch.check_poisoned()?;
+            let end = Self::visible_end(&ch, self.id);
+            let acquired = Self::acquire_record(&mut ch, &mut self.cur, end);
+            if let Some(acquired) = self.channel.or_poison(&mut ch, acquired)? {
+                Self::acquired(&mut ch, self.id, &acquired.0);
+                return Ok(Some(acquired));
+            }
+            if Self::is_drained(&ch, self.id) {
+                return Ok(None);
+            }
+            self.wait(&mut ch, &mut backoff);
+        }
+    }
+
+    /// Returns the next readable region, blocking until one is available.
+    ///
+    /// Returns None once the channel is closed and every committed byte has
+    /// been read.
+    pub fn recv(&mut self) -> Option<Region<'_>> {
+        self.try_recv().ok().flatten()
+    }
+
+    /// Like `recv`, but fails if the channel is poisoned. Returns `Ok(None)`
+    /// once the channel is closed and drained.
+    pub fn try_recv(&mut self) -> Result<Option<Region<'_>>, Error> {
+        Ok(self
+            .try_recv_raw()?
+            .map(|(interval, ptr)| self.region(interval, ptr)))
+    }
+
+    /// Like `recv` but hands back the interval and its address instead of a
+    /// region borrowing the receiver.
+    ///
+    /// The caller is responsible for eventually calling `unreserve`.
+    pub(crate) fn recv_raw(&mut self) -> Option<(Interval, *const u8)> {
+        self.try_recv_raw().ok().flatten()
+    }
+
+    /// Like `recv_raw`, but fails if the channel is poisoned.
+    pub(crate) fn try_recv_raw(&mut self) -> Result<Option<(Interval, *const u8)>, Error> {
+        let mut ch = self.channel.inner.lock();
+        let mut backoff = Backoff::new(&ch);
+        loop {
+            ch.check_poisoned()?;
+            let end = Self::visible_end(&ch, self.id);
+            let acquired = Self::acquire(&mut ch, &mut self.cur, end);
+            if let Some(acquired) = self.channel.or_poison(&mut ch, acquired)? {
+                Self::acquired(&mut ch, self.id, &acquired.0);
+                return Ok(Some(acquired));
+            }
+            if Self::is_drained(&ch, self.id) {
+                return Ok(None);
+            }
+            self.wait(&mut ch, &mut backoff);
+        }
+    }
+
+    /// Waits for data, marking the receiver as waiting in the meantime.
+    fn wait(&self, ch: &mut MutexGuard<'_, RawChannel>, backoff: &mut Backoff) {
+        Self::entry(ch, self.id).waiting = true;
+        backoff.wait(ch, &self.channel.data_available);
+        Self::entry(ch, self.id).waiting = false;
+    }
+
+    fn entry(ch: &mut RawChannel, id: u64) -> &mut ReceiverEntry {
+        ch.receivers.get_mut(&id).expect("receiver is registered")
+    }
+
+    /// Records a region acquired by the receiver `id`.
+    fn acquired(ch: &mut RawChannel, id: u64, interval: &Interval) {
+        let entry = Self::entry(ch, id);
+        entry.cur = interval.end;
+        entry.held.push((interval.beg, sync::current().id()));
+    }
+
+    /// Everything before this has been released by the receiver.
+    fn floor(entry: &ReceiverEntry) -> BegCursor {
+        let held = entry.held.iter().map(|(beg, _)| *beg);
+        held.min().unwrap_or(entry.cur.into())
+    }
+
+    /// How far the receiver `id` may read: up to what its upstream has
+    /// released, or else everything committed.
+    pub(super) fn visible_end(ch: &RawChannel, id: u64) -> EndCursor {
+        match ch.receivers[&id].upstream {
+            Some(up) => Self::floor(&ch.receivers[&up]).into(),
+            None => ch.reads.end,
+        }
+    }
+
+    /// True once no more bytes will become visible to the receiver `id`.
+    pub(super) fn is_drained(ch: &RawChannel, id: u64) -> bool {
+        let end = ch.position(Self::visible_end(ch, id));
+        !ch.is_accepting_writes
+            && ch.outstanding_writes.is_empty()
+            && end.is_some()
+            && end == ch.position(ch.reads.end)
+    }
+
+    /// Wakes receivers that depend on others after this one released bytes.
+    fn notify_dependents(&self, ch: &RawChannel) {
+        if ch.has_dependents() {
+            self.channel.data_available.notify_all();
+        }
+    }
+
+    fn region_mut(&mut self, interval: Interval, ptr: *mut u8) -> RegionMut<'_> {
+        RegionMut {
+            owner: self,
+            cur: interval,
+            buf: unsafe { std::slice::from_raw_parts_mut(ptr, interval.len() as _) },
+        }
+    }
+
+    fn record(&mut self, (interval, ptr, entry): (Interval, *const u8, RecordEntry)) -> Record<'_> {
+        Record {
+            region: self.region(interval, ptr),
+            seq: entry.seq,
+            meta: entry.meta,
+            committed: entry.committed,
+            corrupt: entry.corrupt,
+            crc: entry.crc,
+        }
+    }
+
+    fn region(&mut self, interval: Interval, ptr: *const u8) -> Region<'_> {
+        Region {
+            owner: self,
+            cur: interval,
+            buf: unsafe { std::slice::from_raw_parts(ptr, interval.len() as _) },
+        }
+    }
+
+    /// Reserves the readable bytes following `cur` and advances `cur` past
+    /// them.
+    fn acquire(
+        ch: &mut RawChannel,
+        cur: &mut EndCursor,
+        end: EndCursor,
+    ) -> Result<Option<(Interval, *const u8)>, Violation> {
+        let interval = Self::readable_interval(ch, *cur, end)?;
+        Ok(interval.map(|interval| Self::take(ch, cur, interval)))
+    }
+
+    /// The readable bytes following `cur` up to `end` or the high mark. None
+    /// if there aren't any.
+    ///
+    /// `end` is the read head, or for a dependent receiver what its upstream
+    /// has released.
+    fn readable_interval(
+        ch: &RawChannel,
+        cur: EndCursor,
+        end: EndCursor,
+    ) -> Result<Option<Interval>, Violation> {
+        // These used to be asserts, and the first one fired in production
+        // runs with e.g.
+        //
+        // 'R1' panicked at 'cur:61441(11048) reads:61441(11048)-4895(11049) high:-1'
+        // 'R1' panicked at 'cur:61440(10762) reads:61440(10762)-45073(10763) high:-1'
+        //
+        // Shouldn't high mark be set here. I'm inclined to think this is mostly a fine state
+        // but that high mark should still be set.
+        //
+        // Now they poison the channel instead of taking down the thread while
+        // it holds the lock.
+        if !ch.read_high_mark_holds() {
+            let detail = format!("cur:{} reads:{}", cur, ch.reads);
+            return Err(Violation::new(Invariant::ReadHighMark, detail));
+        }
+        if !(ch.reads.beg <= cur.into() && cur <= ch.reads.end && end <= ch.reads.end) {
+            let detail = format!("cur:{} end:{} reads:{}", cur, end, ch.reads);
+            return Err(Violation::new(Invariant::ReceiverInReads, detail));
+        }
+
+        // Only wrap if there's a cycle difference.
+        //
+        // This is particularly important for the case where `reads.beg`
+        // and `reads.end` are in different cycles, but the `cur` is
+        // at `reads.end` and that happens to correspond to the `high_mark`.
+        let beg = cur.to_beg(if cur.cycle == end.cycle {
+            None
+        } else {
+            ch.reads.high_mark
+        });
+
+        // Compute the interval to read
+        // It will never straddle the cycle boundary so the high_mark
+        // should never be set.
+        let interval = if beg.cycle == end.cycle {
+            Interval {
+                beg,
+                end,
+                high_mark: None,
+            }
+        } else {
+            let high_mark = match ch.reads.high_mark {
+                Some(high_mark) if ch.reads.beg.cycle == beg.cycle => high_mark,
+                _ => {
+                    let detail = format!("beg:{} reads:{}", beg, ch.reads);
+                    return Err(Violation::new(Invariant::ReadHighMark, detail));
                 }
             };
-            assert!(interval.high_mark.is_none());
-            if interval.len() == 0 {
-                return None;
+            Interval {
+                beg,
+                end: EndCursor {
+                    cycle: ch.reads.beg.cycle,
+                    offset: high_mark,
+                },
+                high_mark: None,
             }
+        };
+        if interval.len() == 0 {
+            return Ok(None);
+        }
+        Ok(Some(interval))
+    }
 
-            let ptr = unsafe { ch.ptr.as_ptr().offset(interval.beg.offset) as *const _ };
+    /// Like `acquire` but stops at the end of the record under `cur`.
+    fn acquire_record(
+        ch: &mut RawChannel,
+        cur: &mut EndCursor,
+        end: EndCursor,
+    ) -> Result<Option<(Interval, *const u8, RecordEntry)>, Violation> {
+        let (interval, record) = match Self::record_interval(ch, *cur, end)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let (interval, ptr) = Self::take(ch, cur, interval);
+        Ok(Some((interval, ptr, record)))
+    }
 
-            ch.outstanding_reads.insert(interval.beg);
-            ch.outstanding_reads.remove(&self.cur.into());
-            (interval, ptr)
+    /// The readable part of the record under `cur`, and the record.
+    pub(super) fn record_interval(
+        ch: &RawChannel,
+        cur: EndCursor,
+        end: EndCursor,
+    ) -> Result<Option<(Interval, RecordEntry)>, Violation> {
+        let interval = match Self::readable_interval(ch, cur, end)? {
+            Some(interval) => interval,
+            None => return Ok(None),
         };
-        self.cur = interval.end;
-
-        assert!(interval.len() > 0);
-        if interval.len() > 0 {
-            Some(Region {
-                owner: self,
-                cur: interval,
-                buf: unsafe { std::slice::from_raw_parts(ptr, interval.len() as _) },
+        // Everything before `reads.end` is committed, so the record holding
+        // the first readable byte is complete and ends inside `interval`.
+        let i = ch
+            .records
+            .partition_point(|r| BegCursor::from(r.interval.end) <= interval.beg);
+        let mut record = *ch.records.get(i).ok_or_else(|| {
+            Violation::new(Invariant::Records, format!("no record at {}", interval.beg))
+        })?;
+        // The checksum covers the whole record, not just the rest of it.
+        if interval.beg != record.interval.beg {
+            record.crc = None;
+        }
+        let interval = Interval {
+            end: record.interval.end,
+            ..interval
+        };
+        Ok(Some((interval, record)))
+    }
+
+    /// Reserves `interval`, which starts at `cur`, and moves `cur` to its
+    /// end.
+    fn take(ch: &mut RawChannel, cur: &mut EndCursor, interval: Interval) -> (Interval, *const u8) {
+        let ptr = unsafe { ch.ptr.as_ptr().offset(interval.beg.offset) as *const _ };
+
+        ch.outstanding_reads.insert(interval.beg);
+        ch.outstanding_reads.remove(&(*cur).into());
+        *cur = interval.end;
+
+        debug_assert!(interval.len() > 0);
+        (interval, ptr)
+    }
+
+    /// Writes everything that is currently readable to `out`, blocking until
+    /// something is.
+    ///
+    /// Readable bytes on both sides of the wrap are gathered into a single
+    /// [`Write::write_vectored`] call, so a `File` sees one `writev`. Only the
+    /// bytes `out` accepted are released. Returns the number of bytes
+    /// written, or `Ok(0)` once the channel is closed and drained.
+    pub fn drain_to<W: Write + ?Sized>(&mut self, out: &mut W) -> io::Result<usize> {
+        let spans = match self.try_wait_readable() {
+            Ok(Some(spans)) => spans,
+            Ok(None) => return Ok(0),
+            Err(e) => return Err(io::Error::other(e)),
+        };
+        let bufs = unsafe { spans.io_slices() };
+        let n = out.write_vectored(&bufs)?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        self.advance(&spans, n);
+        Ok(n)
+    }
+
+    /// Blocks until there are readable bytes after `cur` and returns where
+    /// they are without reserving them.
+    ///
+    /// Returns `Ok(None)` once the channel is closed and drained, and fails
+    /// if it's poisoned.
+    pub(crate) fn try_wait_readable(&mut self) -> Result<Option<Spans>, Error> {
+        let mut ch = self.channel.inner.lock();
+        let mut backoff = Backoff::new(&ch);
+        loop {
+            ch.check_poisoned()?;
+            let spans = Self::readable(&ch, self.cur, Self::visible_end(&ch, self.id));
+            let spans = self.channel.or_poison(&mut ch, spans)?;
+            if spans.len() > 0 {
+                return Ok(Some(spans));
+            }
+            if Self::is_drained(&ch, self.id) {
+                return Ok(None);
+            }
+            self.wait(&mut ch, &mut backoff);
+        }
+    }
+
+    /// True if there are readable bytes after `cur`.
+    pub(super) fn has_readable(
+        ch: &RawChannel,
+        cur: EndCursor,
+        end: EndCursor,
+    ) -> Result<bool, Violation> {
+        Ok(Self::readable(ch, cur, end)?.len() > 0)
+    }
+
+    /// The readable bytes between `cur` and `end`, split at the high mark.
+    fn readable(ch: &RawChannel, cur: EndCursor, end: EndCursor) -> Result<Spans, Violation> {
+        let beg = cur.to_beg(if cur.cycle == end.cycle {
+            None
+        } else {
+            ch.reads.high_mark
+        });
+        let base = ch.ptr.as_ptr() as *const u8;
+        let span = |from: isize, to: isize| unsafe { (base.offset(from), (to - from) as usize) };
+        if beg.cycle == end.cycle {
+            Ok(Spans {
+                beg,
+                first: span(beg.offset, end.offset),
+                second: span(0, 0),
             })
         } else {
-            return None;
+            let high_mark = ch.reads.high_mark.ok_or_else(|| {
+                let detail = format!("span from {} to {} without a high mark", beg, end);
+                Violation::new(Invariant::ReadHighMark, detail)
+            })?;
+            Ok(Spans {
+                beg,
+                first: span(beg.offset, high_mark),
+                second: span(0, end.offset),
+            })
         }
     }
 
+    /// Moves `cur` forward by `nbytes` through `spans`, releasing the bytes
+    /// it passes.
+    pub(crate) fn advance(&mut self, spans: &Spans, nbytes: usize) {
+        assert!(nbytes <= spans.len(), "advance {} of {}", nbytes, spans.len());
+        let next = if nbytes <= spans.first.1 {
+            EndCursor {
+                cycle: spans.beg.cycle,
+                offset: spans.beg.offset + nbytes as isize,
+            }
+        } else {
+            EndCursor {
+                cycle: spans.beg.cycle + 1,
+                offset: (nbytes - spans.first.1) as isize,
+            }
+        };
+        let mut ch = self.channel.inner.lock();
+        Self::release(&mut ch, self.cur.into(), next.into());
+        self.cur = next;
+        Self::entry(&mut ch, self.id).cur = next;
+        ch.wake_writers();
+        self.notify_dependents(&ch);
+        ch.wake_watchers();
+    }
+
     pub(crate) fn unreserve(&mut self, interval: &Interval) {
+        let mut ch = self.channel.inner.lock();
+        Self::release(&mut ch, interval.beg, interval.end.into());
+        let held = &mut Self::entry(&mut ch, self.id).held;
+        if let Some(i) = held.iter().position(|(beg, _)| *beg == interval.beg) {
+            held.swap_remove(i);
+        }
+        ch.wake_writers();
+        self.notify_dependents(&ch);
+        ch.wake_watchers();
+    }
+
+    /// Moves a read position from `from` to `to` and updates the read_tail.
+    pub(super) fn release(ch: &mut RawChannel, from: BegCursor, to: BegCursor) {
         // Remove the region and update the read_tail. If this is the last
         // region outstanding then the read_tail corresponds to the end,
         // otherwise it's just the min over all outstanding reads.
-        let mut ch = self.channel.inner.lock();
-        ch.outstanding_reads.remove(&interval.beg);
-        ch.outstanding_reads.insert(interval.end.into());
+        ch.outstanding_reads.remove(&from);
+        ch.outstanding_reads.insert(to);
         let c0 = ch.reads.beg.cycle;
         let before = ch.reads;
-        ch.reads.beg = *ch.outstanding_reads.min().unwrap_or(&interval.end.into());
+        ch.reads.beg = *ch.outstanding_reads.min().unwrap_or(&to);
+        ch.count_released(before.beg);
         let c1 = ch.reads.beg.cycle;
         if c1 > c0 {
             trace!(
-                "unset {} {} reads:{} cur:{} int:{} ch.outstanding_reads:{:?}",
+                "unset {} {} reads:{} from:{} to:{} ch.outstanding_reads:{:?}",
                 c0,
                 c1,
                 before,
-                self.cur,
-                interval,
+                from,
+                to,
                 ch.outstanding_reads
             );
             ch.reads.high_mark = None;
+            ch.prune_cycles();
+        }
+        ch.prune_records();
+    }
+
+    /// Forgets a reader positioned at `cur` and moves the read tail up to
+    /// the remaining ones.
+    pub(super) fn detach(ch: &mut RawChannel, cur: BegCursor) {
+        ch.outstanding_reads.remove(&cur);
+        let before = ch.reads.beg;
+        ch.reads.beg = ch.outstanding_reads.min().copied().unwrap_or(ch.reads.beg);
+        ch.count_released(before);
+        if ch.reads.beg.cycle > before.cycle {
+            ch.reads.high_mark = None;
+            ch.prune_cycles();
+        }
+        ch.prune_records();
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        // Stop holding back the read tail. With no receivers left the tail
+        // stays put so a receiver created later still sees unread data.
+        let mut ch = self.channel.inner.lock();
+        let entry = ch.receivers.remove(&self.id).unwrap();
+        if ch.exclusive == Some(self.id) {
+            ch.exclusive = None;
+        }
+        for r in ch.receivers.values_mut() {
+            if r.upstream == Some(self.id) {
+                r.upstream = entry.upstream;
+            }
+        }
+        Self::detach(&mut ch, self.cur.into());
+        ch.wake_writers();
+        // Receivers that depended on this one may see more now.
+        self.channel.data_available.notify_all();
+        ch.wake_watchers();
+    }
+}
+
+/// Readable bytes that may wrap around the end of the buffer.
+///
+/// `first` runs from `beg` up to the high mark or the read head. `second`
+/// is empty unless the readable bytes wrap, in which case it starts at the
+/// beginning of the buffer.
+pub(crate) struct Spans {
+    pub(crate) beg: BegCursor,
+    pub(crate) first: (*const u8, usize),
+    pub(crate) second: (*const u8, usize),
+}
+
+impl Spans {
+    pub(crate) fn len(&self) -> usize {
+        self.first.1 + self.second.1
+    }
+
+    /// # Safety
+    ///
+    /// The slices are only valid until the receiver advances past them.
+    pub(crate) unsafe fn io_slices<'a>(&self) -> [IoSlice<'a>; 2] {
+        [
+            IoSlice::new(std::slice::from_raw_parts(self.first.0, self.first.1)),
+            IoSlice::new(std::slice::from_raw_parts(self.second.0, self.second.1)),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use std::sync::Arc;
+
+    use crate::base::{channel, cursor::BegCursor, Channel, ChannelFactory, Error, Meta, Position};
+
+    /// Accepts at most `limit` bytes per call.
+    struct Trickle {
+        limit: usize,
+        out: Vec<u8>,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.limit);
+            self.out.extend_from_slice(&buf[..n]);
+            Ok(n)
         }
-        self.channel.space_available.notify_all();
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn drain_to_gathers_across_wrap() {
+        let (mut tx, mut rx) = channel(10);
+        tx.map(6).unwrap().fill(0);
+        assert_eq!(rx.drain_to(&mut io::sink()).unwrap(), 6);
+
+        tx.map(3).unwrap().fill(1);
+        tx.map(4).unwrap().fill(2); // wraps, high mark at 9
+        let mut out = Vec::new();
+        assert_eq!(rx.drain_to(&mut out).unwrap(), 7);
+        assert_eq!(out, [1, 1, 1, 2, 2, 2, 2]);
+        assert!(rx.next().is_none());
+    }
+
+    #[test]
+    fn drain_to_releases_only_accepted_bytes() {
+        let (mut tx, mut rx) = channel(10);
+        tx.map(6).unwrap().fill(0);
+        rx.drain_to(&mut io::sink()).unwrap();
+        tx.map(3).unwrap().copy_from_slice(&[1, 2, 3]);
+        tx.map(4).unwrap().copy_from_slice(&[4, 5, 6, 7]);
+
+        let mut out = Trickle {
+            limit: 2,
+            out: Vec::new(),
+        };
+        tx.channel().close();
+        while rx.drain_to(&mut out).unwrap() > 0 {}
+        assert_eq!(out.out, [1, 2, 3, 4, 5, 6, 7]);
+
+        // Everything was released, so the whole buffer is writable again.
+        let ch = tx.channel().inner.lock();
+        assert_eq!(ch.reads.beg, ch.writes.end.into());
+    }
+
+    #[test]
+    fn dropping_a_lagging_receiver_frees_its_bytes() {
+        let (mut tx, mut rx) = channel(10);
+        let lagging = tx.channel().receiver();
+        tx.map(6).unwrap();
+        while rx.next().is_some() {}
+        assert_eq!(tx.channel().inner.lock().reads.beg.offset, 0);
+
+        drop(lagging);
+        let ch = tx.channel().inner.lock();
+        assert_eq!(ch.reads.beg, ch.writes.end.into());
+    }
+
+    #[test]
+    fn positions_skip_the_space_past_the_high_mark() {
+        let (mut tx, mut rx) = channel(10);
+        let mut written = Vec::new();
+        for n in [6, 3, 4, 5] {
+            let region = tx.map(n).unwrap();
+            written.push(region.position());
+            drop(region);
+            while rx.next().is_some() {}
+        }
+        assert_eq!(written, [Position(0), Position(6), Position(9), Position(13)]);
+
+        tx.map(2).unwrap();
+        tx.map(2).unwrap();
+        let a = rx.next().unwrap();
+        let (pos, len) = (a.position(), a.len() as u64);
+        drop(a);
+        assert_eq!((pos, len), (Position(18), 4));
+        assert_eq!(pos + len - written[0], 22);
+    }
+
+    #[test]
+    fn records_keep_their_boundaries_and_metadata() {
+        let (mut tx, mut rx) = channel(10);
+        let meta = |frame| Meta {
+            frame,
+            ..Meta::default()
+        };
+        tx.map_with_meta(3, meta(10)).unwrap().fill(1);
+        tx.map_with_meta(3, meta(11)).unwrap().fill(2);
+        // Truncated to nothing, so it doesn't get a number.
+        assert!(tx.map_with_meta(2, meta(12)).unwrap().truncate(0));
+
+        let r = rx.next_record().unwrap();
+        assert_eq!((&*r, r.seq(), r.meta().frame), (&[1u8; 3][..], 0, 10));
+        drop(r);
+        let r = rx.next_record().unwrap();
+        assert_eq!((&*r, r.seq(), r.meta().frame), (&[2u8; 3][..], 1, 11));
+        drop(r);
+        assert!(rx.next_record().is_none());
+
+        // Wraps, skipping the last 4 bytes.
+        tx.map_with_meta(5, meta(13)).unwrap().fill(3);
+        tx.map(1).unwrap().fill(4);
+        let r = rx.next_record().unwrap();
+        assert_eq!((r.len(), r.seq(), r.meta().frame), (5, 2, 13));
+        assert_eq!(r.position(), Position(6));
+        drop(r);
+        let r = rx.next_record().unwrap();
+        assert_eq!((&*r, r.seq(), *r.meta()), (&[4u8][..], 3, Meta::default()));
+    }
+
+    #[test]
+    fn next_record_finishes_a_partly_read_region() {
+        let (mut tx, mut rx) = channel(10);
+        let mut region = tx.map(4).unwrap();
+        region.copy_from_slice(&[1, 2, 3, 4]);
+        drop(region);
+        let spans = rx.try_wait_readable().unwrap().unwrap();
+        rx.advance(&spans, 2);
+        let r = rx.next_record().unwrap();
+        assert_eq!((&*r, r.seq()), (&[3u8, 4][..], 0));
+    }
+
+    #[test]
+    fn checksums_catch_stray_writes() {
+        let (mut tx, mut rx) = channel(10);
+        tx.map(2).unwrap().fill(1);
+        tx.channel().set_checksums(true);
+        tx.map(3).unwrap().fill(2);
+        tx.map(3).unwrap().fill(3);
+
+        let r = rx.next_record().unwrap();
+        assert_eq!(r.checksum(), None);
+        assert_eq!(r.verify(), Ok(()));
+        drop(r);
+        let r = rx.next_record().unwrap();
+        assert_eq!(r.checksum(), Some(crc32c::crc32c(&[2; 3])));
+        assert_eq!(r.verify(), Ok(()));
+        drop(r);
+
+        unsafe { *(tx.channel().as_ptr() as *mut u8).add(6) = 0 };
+        let r = rx.next_record().unwrap();
+        assert_eq!(r.verify(), Err(Error::Corrupt { position: Position(5) }));
+    }
+
+    #[test]
+    fn try_recv_record_reports_corrupt_records() {
+        let (mut tx, mut rx) = channel(10);
+        tx.channel().set_checksums(true);
+        tx.map(3).unwrap().fill(1);
+        tx.map(3).unwrap().fill(2);
+        tx.channel().close();
+
+        unsafe { *(tx.channel().as_ptr() as *mut u8).add(1) = 0 };
+        assert_eq!(
+            rx.try_recv_record().map(|r| r.is_some()),
+            Err(Error::Corrupt { position: Position(0) })
+        );
+        assert_eq!(&*rx.try_recv_record().unwrap().unwrap(), &[2u8; 3]);
+        assert!(rx.try_recv_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn dependent_sees_only_what_upstream_released() {
+        let (mut tx, mut first) = channel(10);
+        let mut second = first.dependent();
+        tx.map(4).unwrap().fill(1);
+        tx.map(4).unwrap().fill(2);
+
+        let region = first.next_record().unwrap();
+        assert!(second.next().is_none());
+        drop(region);
+        assert_eq!(&*second.next().unwrap(), &[1u8; 4]);
+        assert!(second.next().is_none());
+
+        // The writer is held back by the last stage.
+        first.next().unwrap();
+        {
+            let ch = tx.channel().inner.lock();
+            assert_eq!(ch.reads.beg, BegCursor { cycle: 0, offset: 4 });
+        }
+
+        // Without its upstream the dependent reads everything committed.
+        tx.map(1).unwrap();
+        drop(first);
+        assert_eq!(second.next().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn pipeline_stages_see_every_byte_in_order() {
+        const COUNT: usize = 500;
+        let (mut tx, first) = channel(64);
+        let mut stages = vec![first.dependent()];
+        stages.push(stages[0].dependent());
+
+        let handles: Vec<_> = std::iter::once(first)
+            .chain(stages)
+            .map(|mut rx| {
+                std::thread::spawn(move || {
+                    let mut out = Vec::new();
+                    while let Some(region) = rx.recv() {
+                        out.extend_from_slice(&region);
+                    }
+                    out
+                })
+            })
+            .collect();
+
+        let mut expected = Vec::new();
+        for i in 0..COUNT {
+            let mut region = tx.map(1 + i % 13).unwrap();
+            region.fill(i as u8);
+            expected.extend_from_slice(&region);
+        }
+        tx.channel().close();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn try_recv_returns_none_once_drained() {
+        let (mut tx, mut rx) = channel(10);
+        tx.map(4).unwrap().fill(1);
+        tx.channel().close();
+        assert_eq!(rx.try_recv().unwrap().unwrap().len(), 4);
+        assert!(rx.try_recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn exclusive_receiver_modifies_in_place() {
+        let ch = Arc::new(Channel::new(10));
+        let mut tx = ch.sender();
+        let mut rx = ch.exclusive_receiver().unwrap();
+        let mut writer = rx.dependent();
+        assert!(ch.exclusive_receiver().is_none());
+
+        tx.map(4).unwrap().copy_from_slice(&[1, 2, 3, 4]);
+        {
+            let mut region = rx.next_mut().unwrap();
+            region.reverse();
+        }
+        assert_eq!(&*writer.next().unwrap(), &[4, 3, 2, 1]);
+
+        drop(rx);
+        drop(writer);
+        let _other = ch.receiver();
+        assert!(ch.exclusive_receiver().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "exclusive receiver")]
+    fn no_other_receivers_beside_an_exclusive_one() {
+        let ch = Arc::new(Channel::new(10));
+        let _rx = ch.exclusive_receiver().unwrap();
+        assert!(ch.try_receiver().is_none());
+        assert!(ch.try_group().is_none());
+        let _ = ch.receiver();
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't exclusive")]
+    fn next_mut_needs_an_exclusive_receiver() {
+        let (mut tx, mut rx) = channel(10);
+        tx.map(1).unwrap();
+        rx.next_mut();
     }
 }
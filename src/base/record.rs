@@ -0,0 +1,129 @@
+//! Metadata that travels alongside each region written to a channel.
+
+use std::{ops::Deref, time::Instant};
+
+use super::{
+    cursor::{Interval, Position},
+    error::Error,
+    region::Region,
+};
+
+/// A fixed size block of metadata attached to a region by
+/// [`Sender::map_with_meta`](super::Sender::map_with_meta).
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Meta {
+    /// When the data was captured, in whatever units the application uses.
+    pub timestamp: u64,
+    /// E.g. the frame number reported by a camera.
+    pub frame: u64,
+    /// Free for the application to use.
+    pub tag: [u8; 16],
+}
+
+/// One region as it was committed by a sender, along with its metadata.
+///
+/// Returned by [`Receiver::next_record`](super::Receiver::next_record).
+/// Released when dropped, like a [`Region`].
+pub struct Record<'a> {
+    pub(crate) region: Region<'a>,
+    pub(crate) seq: u64,
+    pub(crate) meta: Meta,
+    pub(crate) committed: Instant,
+    pub(crate) corrupt: bool,
+    pub(crate) crc: Option<u32>,
+}
+
+impl<'a> Record<'a> {
+    pub fn meta(&self) -> &Meta {
+        &self.meta
+    }
+
+    /// The sequence number the channel gave the region when it was
+    /// committed.
+    ///
+    /// Numbers are consecutive in stream order. Regions truncated to nothing
+    /// aren't published, so they don't get one.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn position(&self) -> Position {
+        self.region.position()
+    }
+
+    /// When the sender committed the region.
+    pub fn committed(&self) -> Instant {
+        self.committed
+    }
+
+    /// True if the sender panicked before it finished writing the region,
+    /// so the bytes may be incomplete.
+    ///
+    /// A region abandoned like that is normally discarded. It's only
+    /// committed with this flag when later regions were already reserved.
+    /// Only records carry the flag: [`Receiver::next`](super::Receiver::next)
+    /// and the other calls that read bytes rather than records can't tell.
+    /// Readers like that should have the channel
+    /// [poison on panic](super::Channel::set_poison_on_panic) instead.
+    pub fn is_corrupt(&self) -> bool {
+        self.corrupt
+    }
+
+    /// The CRC32C of the region taken when it was committed.
+    ///
+    /// None unless the channel [computes checksums](super::Channel::set_checksums),
+    /// or if this is only the rest of a region `next` already returned part of.
+    pub fn checksum(&self) -> Option<u32> {
+        self.crc
+    }
+
+    /// Fails with [`Error::Corrupt`] if the bytes changed since they were
+    /// committed. Passes if there's no [`checksum`](Self::checksum).
+    pub fn verify(&self) -> Result<(), Error> {
+        verify(&self.region, self.crc, || self.position())
+    }
+}
+
+impl<'a> Deref for Record<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.region
+    }
+}
+
+impl<'a> AsRef<[u8]> for Record<'a> {
+    fn as_ref(&self) -> &[u8] {
+        &self.region
+    }
+}
+
+/// The channel's entry for a reserved region.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RecordEntry {
+    pub(crate) interval: Interval,
+    pub(crate) seq: u64,
+    pub(crate) meta: Meta,
+    /// When the region was committed, or reserved until then.
+    pub(crate) committed: Instant,
+    /// Set when the sender panicked while filling the region.
+    pub(crate) corrupt: bool,
+    /// CRC32C of the bytes as they were committed, if the channel computes
+    /// checksums.
+    pub(crate) crc: Option<u32>,
+}
+
+/// Checks `bytes` against the checksum taken when they were committed.
+/// `position` is only asked for when they don't match.
+pub(crate) fn verify(
+    bytes: &[u8],
+    crc: Option<u32>,
+    position: impl FnOnce() -> Position,
+) -> Result<(), Error> {
+    match crc {
+        Some(crc) if crc32c::crc32c(bytes) != crc => Err(Error::Corrupt {
+            position: position(),
+        }),
+        _ => Ok(()),
+    }
+}
@@ -1,7 +1,7 @@
 use std::ops::{Deref, DerefMut};
 
 use super::{
-    cursor::{BegCursor, EndCursor, Interval},
+    cursor::{BegCursor, EndCursor, Interval, Position},
     receiver::Receiver,
     sender::Sender,
 };
@@ -10,12 +10,60 @@ use super::{
 //  MutRegion
 //
 
+/// A region reserved by a [`Sender`]. Committed when dropped.
+///
+/// If it's dropped while the thread is panicking, the bytes are likely
+/// incomplete, so it's discarded or marked corrupt instead. See
+/// [`Record::is_corrupt`](super::Record::is_corrupt).
 pub struct MutRegion<'a> {
-    pub(crate) owner: &'a mut Sender,
+    pub(crate) owner: &'a Sender,
     pub(crate) cur: Interval,
     pub(crate) buf: &'a mut [u8],
 }
 
+impl<'a> MutRegion<'a> {
+    /// Where the region starts in the channel's stream.
+    pub fn position(&self) -> Position {
+        self.owner.channel().held_position(self.cur.beg)
+    }
+
+    /// Shortens the region to its first `len` bytes so that only those are
+    /// committed when the region is dropped.
+    ///
+    /// This only works when the region is the most recent reservation on
+    /// the channel. Otherwise returns false and leaves the region unchanged.
+    /// Has no effect if `len` is not less than the region's length.
+    pub fn truncate(&mut self, len: usize) -> bool {
+        if len >= self.buf.len() {
+            return true;
+        }
+        match self.owner.shrink(&self.cur, len) {
+            Some(cur) => {
+                self.cur = cur;
+                let buf = std::mem::take(&mut self.buf);
+                self.buf = &mut buf[..len];
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Gives the region back without publishing its bytes, e.g. when it
+    /// couldn't be filled. If a later reservation is in the way, the channel
+    /// is poisoned with `why` instead.
+    pub(crate) fn discard(self, why: &str) {
+        self.owner.discard(&self.cur, why);
+        std::mem::forget(self);
+    }
+
+    /// Commits the region as a copy of a record that was `corrupt` and had
+    /// checksum `crc`. See [`Sender::unreserve_copy`].
+    pub(crate) fn commit_copy(self, corrupt: bool, crc: Option<u32>) {
+        self.owner.unreserve_copy(&self.cur, corrupt, crc);
+        std::mem::forget(self);
+    }
+}
+
 impl<'a> AsMut<[u8]> for MutRegion<'a> {
     fn as_mut(&mut self) -> &mut [u8] {
         self.buf
@@ -38,7 +86,107 @@ impl<'a> DerefMut for MutRegion<'a> {
 
 impl<'a> Drop for MutRegion<'a> {
     fn drop(&mut self) {
-        self.owner.unreserve(&self.cur);
+        if std::thread::panicking() {
+            self.owner.abandon(&self.cur);
+        } else {
+            self.owner.unreserve(&self.cur);
+        }
+    }
+}
+
+//
+//  MutBatch
+//
+
+/// A run of consecutive mutable regions reserved together by
+/// [`Sender::map_many`].
+///
+/// Dropping the batch commits every region it still holds with a single
+/// acquisition of the channel lock. Like a [`MutRegion`], the regions are
+/// given up instead when it's dropped during a panic.
+pub struct MutBatch<'a> {
+    pub(crate) owner: &'a Sender,
+    pub(crate) regions: Vec<(Interval, &'a mut [u8])>,
+}
+
+impl<'a> MutBatch<'a> {
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut [u8]> {
+        self.regions.get_mut(index).map(|(_, buf)| &mut **buf)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut [u8]> + use<'_, 'a> {
+        self.regions.iter_mut().map(|(_, buf)| &mut **buf)
+    }
+
+    /// Shortens the batch to its first `len` bytes, counted across all its
+    /// regions. Regions left empty are dropped from the end of the batch.
+    ///
+    /// Like [`MutRegion::truncate`] this only works when the batch holds the
+    /// most recent reservation on the channel. Otherwise returns false.
+    pub fn truncate(&mut self, len: usize) -> bool {
+        let mut start: usize = self.regions.iter().map(|(_, buf)| buf.len()).sum();
+        while let Some((cur, buf)) = self.regions.last_mut() {
+            start -= buf.len();
+            let keep = len.saturating_sub(start);
+            if keep >= buf.len() {
+                return true;
+            }
+            match self.owner.shrink(cur, keep) {
+                Some(next) => *cur = next,
+                None => return false,
+            }
+            if keep > 0 {
+                let b = std::mem::take(buf);
+                *buf = &mut b[..keep];
+                return true;
+            }
+            // Commit the empty region right away so it doesn't stand in the
+            // way of shrinking the one before it.
+            let (cur, _) = self.regions.pop().unwrap();
+            self.owner.unreserve(&cur);
+        }
+        true
+    }
+
+    /// Like [`MutRegion::discard`] for every region in the batch.
+    pub(crate) fn discard(mut self, why: &str) {
+        // Last first, so each one is the most recent reservation in turn.
+        for (cur, _) in std::mem::take(&mut self.regions).iter().rev() {
+            self.owner.discard(cur, why);
+        }
+    }
+
+    /// Splits the batch into individual regions so each can be committed
+    /// on its own when it is dropped.
+    pub fn into_regions(mut self) -> Vec<MutRegion<'a>> {
+        let owner = self.owner;
+        std::mem::take(&mut self.regions)
+            .into_iter()
+            .map(|(cur, buf)| MutRegion { owner, cur, buf })
+            .collect()
+    }
+}
+
+impl<'a> Drop for MutBatch<'a> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            // Last first, so each one is the most recent reservation in turn
+            // and can be discarded.
+            for (cur, _) in self.regions.iter().rev() {
+                self.owner.abandon(cur);
+            }
+        } else if !self.regions.is_empty() {
+            self.owner
+                .unreserve_many(self.regions.iter().map(|(cur, _)| cur));
+        }
     }
 }
 
@@ -57,6 +205,13 @@ impl<'a> Region<'a> {
     pub fn cycle(&self) -> isize {
         self.cur.beg.cycle
     }
+
+    /// Where the region starts in the channel's stream.
+    ///
+    /// The region covers the bytes from here up to `position() + len()`.
+    pub fn position(&self) -> Position {
+        self.owner.channel().held_position(self.cur.beg)
+    }
 }
 
 impl<'a> Deref for Region<'a> {
@@ -78,3 +233,48 @@ impl<'a> Drop for Region<'a> {
         self.owner.unreserve(&self.cur);
     }
 }
+
+//
+// RegionMut
+//
+
+/// A region returned by [`Receiver::next_mut`]. The receiver is the only
+/// one reading the channel, so it may modify the bytes in place.
+pub struct RegionMut<'a> {
+    pub(crate) owner: &'a mut Receiver,
+    pub(crate) cur: Interval,
+    pub(crate) buf: &'a mut [u8],
+}
+
+impl<'a> RegionMut<'a> {
+    /// Where the region starts in the channel's stream.
+    pub fn position(&self) -> Position {
+        self.owner.channel().held_position(self.cur.beg)
+    }
+}
+
+impl<'a> Deref for RegionMut<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.buf
+    }
+}
+
+impl<'a> DerefMut for RegionMut<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.buf
+    }
+}
+
+impl<'a> AsMut<[u8]> for RegionMut<'a> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.buf
+    }
+}
+
+impl<'a> Drop for RegionMut<'a> {
+    fn drop(&mut self) {
+        self.owner.unreserve(&self.cur);
+    }
+}
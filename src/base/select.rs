@@ -0,0 +1,201 @@
+//! Waiting on several channels at once.
+
+use std::sync::Arc;
+
+use super::{
+    channel::{Channel, RawChannel},
+    receiver::Receiver,
+    sender::Sender,
+    sync::{Condvar, Mutex},
+};
+
+/// Wakes a [`Select`] when anything changes on a channel it watches.
+///
+/// Channels hold on to the signals registered with them and notify each one
+/// whenever they notify their own readers or writers.
+#[derive(Default, Debug)]
+pub(crate) struct Signal {
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl Signal {
+    pub(crate) fn notify(&self) {
+        *self.generation.lock() += 1;
+        self.changed.notify_all();
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        *self.generation.lock()
+    }
+
+    /// Blocks until `notify` is called after `generation` was read.
+    pub(crate) fn wait(&self, generation: u64) {
+        let mut g = self.generation.lock();
+        while *g == generation {
+            self.changed.wait(&mut g);
+        }
+    }
+}
+
+enum Operation {
+    Recv { id: u64 },
+    Send { nbytes: usize },
+}
+
+/// Blocks until one of several receivers has something to read, or one of
+/// several senders could map a region without waiting.
+///
+/// ```no_run
+/// # use gyoll::base::{channel, Select};
+/// let (_tx1, mut rx1) = channel(1 << 20);
+/// let (_tx2, mut rx2) = channel(1 << 20);
+/// let mut select = Select::new();
+/// let a = select.recv(&rx1);
+/// let b = select.recv(&rx2);
+/// match select.ready() {
+///     i if i == a => drop(rx1.next()),
+///     _ => drop(rx2.next()),
+/// }
+/// ```
+///
+/// Receivers also count as ready once their channel is closed and drained,
+/// and senders once their channel is closed, so that the caller finds out
+/// from `next` or `map` returning None.
+///
+/// Readiness is only a hint when other threads use the same channels. E.g.
+/// another sender may take the space first, in which case `map` blocks.
+#[derive(Default)]
+pub struct Select {
+    operations: Vec<(Arc<Channel>, Operation)>,
+}
+
+impl Select {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a receiver. Returns the index `ready` uses to refer to it.
+    pub fn recv(&mut self, rx: &Receiver) -> usize {
+        self.push(rx.channel(), Operation::Recv { id: rx.id() })
+    }
+
+    /// Adds a sender that wants to map `nbytes`. Returns the index `ready`
+    /// uses to refer to it.
+    pub fn send(&mut self, tx: &Sender, nbytes: usize) -> usize {
+        self.push(tx.channel(), Operation::Send { nbytes })
+    }
+
+    fn push(&mut self, channel: &Arc<Channel>, operation: Operation) -> usize {
+        self.operations.push((channel.clone(), operation));
+        self.operations.len() - 1
+    }
+
+    /// The index of the first operation that is ready, if any.
+    pub fn try_ready(&self) -> Option<usize> {
+        self.operations
+            .iter()
+            .position(|(channel, operation)| is_ready(&channel.inner.lock(), operation))
+    }
+
+    /// Blocks until an operation is ready and returns its index.
+    ///
+    /// # Panics
+    ///
+    /// If no operations were added.
+    pub fn ready(&self) -> usize {
+        assert!(!self.operations.is_empty(), "nothing to select");
+        let signal = Arc::new(Signal::default());
+        for (channel, _) in &self.operations {
+            channel.watch(signal.clone());
+        }
+
+        let ready = loop {
+            // Read the generation first so a notification that arrives while
+            // checking isn't missed.
+            let generation = signal.generation();
+            if let Some(i) = self.try_ready() {
+                break i;
+            }
+            signal.wait(generation);
+        };
+
+        for (channel, _) in &self.operations {
+            channel.unwatch(&signal);
+        }
+        ready
+    }
+}
+
+fn is_ready(ch: &RawChannel, operation: &Operation) -> bool {
+    // Poisoning also closes the channel.
+    let closed = !ch.is_accepting_writes;
+    match *operation {
+        Operation::Recv { id } => match ch.receivers.get(&id) {
+            // A broken channel counts as ready so the caller finds out.
+            Some(r) => {
+                Receiver::has_readable(ch, r.cur, Receiver::visible_end(ch, id)).unwrap_or(true)
+                    || Receiver::is_drained(ch, id)
+            }
+            // The receiver is gone, so there's nothing to wait for.
+            None => true,
+        },
+        Operation::Send { nbytes } => {
+            let inc = ch.writes.end.next_region(nbytes, ch.capacity);
+            closed || nbytes > ch.capacity || !ch.collides_with_readers(&inc.end)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread::spawn, time::Duration};
+
+    use super::Select;
+    use crate::base::channel;
+
+    #[test]
+    fn select_wakes_on_the_channel_that_became_ready() {
+        let (_tx1, rx1) = channel(10);
+        let (mut tx2, mut rx2) = channel(10);
+        let (mut tx3, mut rx3) = channel(10);
+        tx3.map(10).unwrap();
+
+        let mut select = Select::new();
+        select.recv(&rx1);
+        let b = select.recv(&rx2);
+        let c = select.send(&tx3, 5);
+        assert_eq!(select.try_ready(), None);
+
+        let writer = spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            tx2.map(4).unwrap().fill(1);
+            tx2
+        });
+        assert_eq!(select.ready(), b);
+        assert_eq!(rx2.next().unwrap().len(), 4);
+        writer.join().unwrap();
+
+        let reader = spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            rx3.next().unwrap();
+            rx3
+        });
+        assert_eq!(select.ready(), c);
+        reader.join().unwrap();
+        assert!(tx3.map(5).is_some());
+        drop(rx1);
+    }
+
+    #[test]
+    fn a_wrap_past_caught_up_readers_is_ready_to_send() {
+        let (mut tx, mut rx) = channel(32);
+        tx.map(20).unwrap();
+        let mut select = Select::new();
+        select.send(&tx, 25);
+        assert_eq!(select.try_ready(), None);
+
+        while rx.next().is_some() {}
+        assert_eq!(select.try_ready(), Some(0));
+    }
+}
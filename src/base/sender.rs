@@ -1,4 +1,4 @@
-use std::{mem::size_of_val, sync::Arc};
+use std::{mem::size_of_val, sync::Arc, time::Instant};
 
 use log::{info, trace, warn};
 use parking_lot::lock_api::RawRwLockUpgrade;
@@ -8,9 +8,14 @@ use crate::base::cursor::EndCursor;
 
 use super::cursor::Interval;
 use super::{
-    channel::{Channel, RawChannel},
+    channel::{Channel, RawChannel, WaitingWriter},
     cursor::BegCursor,
-    region::MutRegion,
+    error::Error,
+    record::{Meta, RecordEntry},
+    region::{MutBatch, MutRegion},
+    sync::{self, Condvar, MutexGuard, Ordering},
+    validate::{Invariant, Violation},
+    wait::Backoff,
 };
 
 pub struct Sender {
@@ -34,127 +39,526 @@ impl Sender {
     ///
     /// Blocks until a region is available.
     ///
-    /// Returns None when the channel is unwritable or `nbytes` exceeds the
-    /// channels `capacity`.
-    pub fn map(&mut self, nbytes: usize) -> Option<MutRegion> {
-        let (cur, ptr) = {
-            let mut ch = self.channel.inner.lock();
+    /// Returns None when the channel is unwritable, `nbytes` exceeds the
+    /// channels `capacity`, or waiting would deadlock. See [`try_map`].
+    ///
+    /// [`try_map`]: Self::try_map
+    pub fn map(&mut self, nbytes: usize) -> Option<MutRegion<'_>> {
+        self.try_map(nbytes).ok()
+    }
 
-            if nbytes > ch.capacity || !ch.is_accepting_writes {
-                return None;
-            }
+    /// Like `map`, but says why a region couldn't be reserved.
+    ///
+    /// Fails with [`Error::WouldDeadlock`] instead of blocking when the
+    /// space can only be freed by regions the calling thread holds, e.g. a
+    /// `Region` it's still reading or a `MutRegion` mapped from another
+    /// sender. A region counts as held by the thread that acquired it, even
+    /// if it was handed to another thread since.
+    pub fn try_map(&mut self, nbytes: usize) -> Result<MutRegion<'_>, Error> {
+        let (cur, ptr) = self.try_map_raw(nbytes, Meta::default())?;
 
-            let prev = (ch.writes.beg,ch.writes.end);
-            let inc = ch.writes.end.next_region(nbytes, ch.capacity);
+        // Finally, construct the region
+        let buf = unsafe { std::slice::from_raw_parts_mut(ptr, nbytes) };
+        Ok(MutRegion {
+            owner: self,
+            cur,
+            buf,
+        })
+    }
 
-            // Reserve the region even though we haven't fully acquired it yet.
-            //
-            
-            // The new region will be > any outstanding_write, but if there
-            // are none, we need to update the write_tail. This will assist
-            // with wrapping around a cycle sometimes.
-            //
-            // The min is to deal with when there are multiple blocked write
-            // requests waiting.
-            // if ch.outstanding_writes.is_empty() {
-                // warn!("HERE");
-                // ch.writes.beg = ch.writes.beg.min(inc.beg);
-            // }
-            ch.writes.end = inc.end;
-            assert!(ch.writes.end>=ch.writes.beg.into());
-            ch.outstanding_writes.insert(inc);
-
-            fn collide(w: &EndCursor, r: &BegCursor) -> bool {
-                // On the same cycle, there can be no collision bc enforce
-                // r<=w elsewhere. Otherwise,
-                w.cycle > r.cycle && (w.offset > r.offset || w.cycle > r.cycle + 1)
-                // The w.cycle>r.cycle+1 case handles when the first unread
-                // byte is hanging off the end of the cycle.
-            }
-
-            while collide(&inc.end, &ch.reads.beg) && ch.is_accepting_writes {
-                trace!("     - {} r:{}", inc, ch.reads.beg);
-                self.channel.space_available.wait(&mut ch);
-                trace!("exit - {} r:{}", inc, ch.reads.beg);
-            }
+    /// Like `map` but hands back the reserved interval and its address
+    /// instead of a region borrowing the sender.
+    ///
+    /// The caller is responsible for eventually calling `unreserve`.
+    pub(crate) fn map_raw(&self, nbytes: usize) -> Result<(Interval, *mut u8), Error> {
+        self.try_map_raw(nbytes, Meta::default())
+    }
+
+    /// Like `map`, attaching `meta` to the region. Receivers get it back
+    /// from [`Receiver::next_record`](super::Receiver::next_record) along
+    /// with a sequence number assigned by the channel.
+    pub fn map_with_meta(&mut self, nbytes: usize, meta: Meta) -> Option<MutRegion<'_>> {
+        self.try_map_with_meta(nbytes, meta).ok()
+    }
+
+    /// Like `map_with_meta`, but says why a region couldn't be reserved.
+    /// See [`try_map`](Self::try_map).
+    pub fn try_map_with_meta(&mut self, nbytes: usize, meta: Meta) -> Result<MutRegion<'_>, Error> {
+        let (cur, ptr) = self.try_map_raw(nbytes, meta)?;
+        let buf = unsafe { std::slice::from_raw_parts_mut(ptr, nbytes) };
+        Ok(MutRegion {
+            owner: self,
+            cur,
+            buf,
+        })
+    }
 
+    fn try_map_raw(&self, nbytes: usize, meta: Meta) -> Result<(Interval, *mut u8), Error> {
+        let mut ch = self.channel.inner.lock();
+
+        ch.check_poisoned()?;
+        if !ch.is_accepting_writes {
+            return Err(Error::Closed);
+        }
+        if nbytes > ch.capacity {
+            return Err(Error::TooLarge);
+        }
+
+        let inc = ch.writes.end.next_region(nbytes, ch.capacity);
+        self.reserve(&mut ch, std::slice::from_ref(&inc), meta)?;
+
+        let ptr = unsafe { ch.ptr.as_ptr().offset(inc.beg.offset) };
+        Ok((inc, ptr))
+    }
+
+    /// Like `map_raw`, but the region must start at `offset` in the buffer,
+    /// so that the channel repeats regions made in another one sharing its
+    /// buffer. See [`crate::io::shm`].
+    ///
+    /// Returns None if the write head can't be followed by a region at
+    /// `offset`, e.g. because another sender moved it.
+    pub(crate) fn map_raw_at(
+        &self,
+        offset: usize,
+        nbytes: usize,
+    ) -> Result<Option<(Interval, *mut u8)>, Error> {
+        let mut ch = self.channel.inner.lock();
+
+        ch.check_poisoned()?;
+        if !ch.is_accepting_writes {
+            return Err(Error::Closed);
+        }
+        let inc = match ch.writes.end.region_at(offset, nbytes, ch.capacity) {
+            Some(inc) => inc,
+            None => return Ok(None),
+        };
+        self.reserve(&mut ch, std::slice::from_ref(&inc), Meta::default())?;
+
+        let ptr = unsafe { ch.ptr.as_ptr().offset(inc.beg.offset) };
+        Ok(Some((inc, ptr)))
+    }
+
+    /// Reserves a run of consecutive regions, one for each entry in `sizes`,
+    /// with a single acquisition of the channel lock.
+    ///
+    /// Blocks until the whole run is available. The regions may be filled
+    /// and committed together when the batch is dropped, or split up with
+    /// [`MutBatch::into_regions`] and committed individually.
+    ///
+    /// Returns None when the channel is unwritable, the run can't fit in
+    /// the channel's `capacity`, or waiting would deadlock.
+    pub fn map_many(&mut self, sizes: &[usize]) -> Option<MutBatch<'_>> {
+        self.try_map_many(sizes).ok()
+    }
+
+    /// Like `map_many`, but says why the run couldn't be reserved. See
+    /// [`try_map`](Self::try_map).
+    pub fn try_map_many(&mut self, sizes: &[usize]) -> Result<MutBatch<'_>, Error> {
+        let pieces = {
+            let mut ch = self.channel.inner.lock();
+
+            ch.check_poisoned()?;
             if !ch.is_accepting_writes {
-                // Once the channel stops accepting writes, it cannot be
-                // reopened. There may be some outstanding mutable regions.
-                // When these get released they'll update the write_tail
-                // appropriately. The write_head should move back to the start
-                // of the uncommitted region. This is just a min over all the
-                // outstanding prev_write_heads.
-                //
-                // While threads are waking the write_head is in an undefined
-                // state. No write's are incoming. The only dependency to
-                // worry about is write_tail, which defaults to write_head
-                // when there are no outstanding regions. But that's precisely
-                // the point where write_head is guaranteed to be correct.
-                ch.outstanding_writes.remove(&inc);
-                ch.writes.end = ch.writes.end.min(prev.1);
-                if ch.writes.end<ch.writes.beg.to_end(inc.high_mark){
-                    warn!("{}",ch);
+                return Err(Error::Closed);
+            }
+            if sizes.iter().any(|&n| n > ch.capacity) {
+                return Err(Error::TooLarge);
+            }
+
+            let mut end = ch.writes.end;
+            let run: Vec<Interval> = sizes
+                .iter()
+                .map(|&n| {
+                    let inc = end.next_region(n, ch.capacity);
+                    end = inc.end;
+                    inc
+                })
+                .collect();
+
+            // The last region of the run must not overwrite the first.
+            if let (Some(first), Some(last)) = (run.first(), run.last()) {
+                if collide(&last.end, &first.beg) {
+                    return Err(Error::TooLarge);
                 }
-                return None;
             }
 
-            assert!(
-                inc.beg.cycle - ch.reads.beg.cycle < 2,
-                "inc:{} r:{} ch:{}",
-                inc,
-                ch.reads.beg,
-                ch
-            );
+            self.reserve(&mut ch, &run, Meta::default())?;
 
-            // At this point there's space available so we're ready to reserve
-            // the region.
+            let base = ch.ptr.as_ptr();
+            run.into_iter()
+                .map(|inc| (inc, unsafe { base.offset(inc.beg.offset) }))
+                .collect::<Vec<_>>()
+        };
 
-            // If this increment causes a wrap, then record that as the high
-            // mark for the "writes" interval. Later this will be used to set
-            // the high mark for the "reads".
+        let regions = pieces
+            .into_iter()
+            .map(|(cur, ptr)| {
+                let buf = unsafe { std::slice::from_raw_parts_mut(ptr, cur.len() as usize) };
+                (cur, buf)
+            })
+            .collect();
+        Ok(MutBatch {
+            owner: self,
+            regions,
+        })
+    }
+
+    /// Reserves a run of consecutive intervals computed from the current
+    /// `writes.end`, waiting until the last of them is free.
+    ///
+    /// Each interval gets a record carrying `meta`. Records are added before
+    /// waiting so that their order matches the stream.
+    ///
+    /// Fails if the channel stopped accepting writes while waiting, or if
+    /// waiting would deadlock. In that case the run has been given back.
+    fn reserve(
+        &self,
+        ch: &mut MutexGuard<'_, RawChannel>,
+        run: &[Interval],
+        meta: Meta,
+    ) -> Result<(), Error> {
+        let (first, last) = match (run.first(), run.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return Ok(()),
+        };
+
+        // Reserve the region even though we haven't fully acquired it yet.
+        ch.writes.end = last.end;
+        if ch.writes.end < ch.writes.beg.into() {
+            let detail = format!("writes:{}", ch.writes);
+            let violation = Violation::new(Invariant::CursorOrder, detail);
+            return self.channel.or_poison(ch, Err(violation));
+        }
+        for inc in run {
+            ch.outstanding_writes.insert(*inc);
             if inc.high_mark.is_some() {
-                trace!("latch {}", inc);
-                ch.writes.high_mark = inc.high_mark;
+                if let Err(violation) = ch.start_cycle(inc) {
+                    return self.channel.or_poison(ch, Err(violation));
+                }
+            }
+            ch.records.push_back(RecordEntry {
+                interval: *inc,
+                // Numbered once it's committed. See `number_records`.
+                seq: 0,
+                meta,
+                committed: Instant::now(),
+                corrupt: false,
+                crc: None,
+            });
+        }
+
+        // Regions in the run are ordered, so if the last one is clear of the
+        // readers, so are the others.
+        //
+        // Blocked writers queue up in the order they reserved, which is also
+        // the order the readers free their space, so a release only needs to
+        // wake the writers at the front. See `RawChannel::wake_writers`.
+        if ch.collides_with_readers(&last.end) && ch.is_accepting_writes {
+            // This thread can't let go of what it holds while it waits.
+            if ch.held_up_by_current_thread(&last.end) {
+                Self::retract(ch, run);
+                return Err(Error::WouldDeadlock);
+            }
+            let t0 = Instant::now();
+            let ticket = ch.next_ticket;
+            ch.next_ticket += 1;
+            let wake = Arc::new(Condvar::new());
+            ch.waiting_writers.push_back(WaitingWriter {
+                ticket,
+                end: last.end,
+                wake: wake.clone(),
+            });
+            let mut backoff = Backoff::new(ch);
+            while ch.collides_with_readers(&last.end) && ch.is_accepting_writes {
+                trace!("     - {} r:{}", last, ch.reads.beg);
+                backoff.wait(ch, &wake);
+                trace!("exit - {} r:{}", last, ch.reads.beg);
+            }
+            // Normally `wake_writers` already took us off the queue.
+            ch.waiting_writers.retain(|w| w.ticket != ticket);
+            ch.counters.writer_waits += 1;
+            ch.counters.writer_wait_time += t0.elapsed();
+        }
+
+        ch.check_poisoned()?;
+        if !ch.is_accepting_writes {
+            // Once the channel stops accepting writes, it cannot be
+            // reopened. There may be some outstanding mutable regions.
+            // When these get released they'll update the write_tail
+            // appropriately. The write_head should move back to the start
+            // of the uncommitted region. This is just a min over all the
+            // outstanding prev_write_heads.
+            //
+            // While threads are waking the write_head is in an undefined
+            // state. No write's are incoming. The only dependency to
+            // worry about is write_tail, which defaults to write_head
+            // when there are no outstanding regions. But that's precisely
+            // the point where write_head is guaranteed to be correct.
+            Self::retract(ch, run);
+            // Readers waiting for the channel to drain were waiting on this
+            // reservation too.
+            self.channel.data_available.notify_all();
+            ch.wake_watchers();
+            return Err(Error::Closed);
+        }
+
+        if first.beg.cycle - ch.reads.beg.cycle >= 2 {
+            let detail = format!("inc:{} r:{}", first, ch.reads.beg);
+            let violation = Violation::new(Invariant::ReservationInReach, detail);
+            return self.channel.or_poison(ch, Err(violation));
+        }
+
+        // At this point there's space available so we're ready to reserve
+        // the region.
+
+        // If this increment causes a wrap, then record that as the high
+        // mark for the "writes" interval. Later this will be used to set
+        // the high mark for the "reads".
+        for inc in run.iter().filter(|inc| inc.high_mark.is_some()) {
+            trace!("latch {}", inc);
+            ch.writes.high_mark = inc.high_mark;
+            ch.counters.wraps += 1;
+        }
+        for inc in run {
+            ch.reserved_by.insert(inc.beg, sync::current().id());
+        }
+        if let Some(occupancy) = ch.occupancy() {
+            ch.counters.peak_occupancy = ch.counters.peak_occupancy.max(occupancy);
+        }
+        Ok(())
+    }
+
+    /// Gives back a run that was reserved but never handed out.
+    ///
+    /// Other senders may have reserved after the run, so the write head only
+    /// moves back to the end of the latest reservation that's left.
+    fn retract(ch: &mut RawChannel, run: &[Interval]) {
+        for inc in run {
+            ch.outstanding_writes.remove(inc);
+            if let Some(i) = ch.find_record(inc) {
+                ch.records.remove(i);
             }
+        }
+        let head = ch.records.back().map_or(ch.reads.end, |r| r.interval.end);
+        ch.writes.end = ch.writes.end.min(head);
+        // Forget the cycle the run started, unless a later reservation is in
+        // it too.
+        if let Some(wrap) = run.iter().find(|inc| inc.high_mark.is_some()) {
+            if ch.writes.end.cycle < wrap.beg.cycle {
+                ch.cycle_bases.remove(&wrap.beg.cycle);
+            }
+        }
+        if ch.writes.end < ch.writes.beg.to_end(run[0].high_mark) {
+            warn!("{}", ch);
+        }
+    }
+
+    /// Where in the buffer the write head is. Like `head_room`, only a hint.
+    pub(crate) fn offset(&self) -> usize {
+        self.channel.inner.lock().writes.end.offset as usize
+    }
+
+    /// Bytes left between the write head and the end of the buffer.
+    ///
+    /// Only a hint: other senders may move the head at any time.
+    pub(crate) fn head_room(&self) -> usize {
+        let ch = self.channel.inner.lock();
+        ch.capacity - ch.writes.end.offset as usize
+    }
+
+    /// Shrinks an outstanding reservation to its first `nbytes`, giving the
+    /// rest back to the channel.
+    ///
+    /// Space can only be given back by the most recent reservation. Returns
+    /// the shrunken interval, or None if a later reservation is in the way.
+    pub(crate) fn shrink(&self, interval: &Interval, nbytes: usize) -> Option<Interval> {
+        let mut ch = self.channel.inner.lock();
+        if ch.writes.end != interval.end || !ch.outstanding_writes.contains(interval) {
+            return None;
+        }
 
-            let ptr = unsafe { ch.ptr.as_ptr().offset(inc.beg.offset) };
-            (inc, ptr)
+        let next = if nbytes == 0 && interval.high_mark.is_some() {
+            // Nothing was written after wrapping, so undo the wrap. This
+            // returns the head to the end of the previous cycle.
+            let end = interval.beg.to_end(interval.high_mark);
+            ch.writes.high_mark = None;
+            ch.counters.wraps -= 1;
+            ch.cycle_bases.remove(&interval.beg.cycle);
+            Interval {
+                beg: end.into(),
+                end,
+                high_mark: None,
+            }
+        } else {
+            Interval {
+                end: EndCursor {
+                    cycle: interval.beg.cycle,
+                    offset: interval.beg.offset + nbytes as isize,
+                },
+                ..*interval
+            }
         };
+        if next.end > interval.end {
+            let detail = format!("next:{} interval:{}", next, interval);
+            let violation = Violation::new(Invariant::CursorOrder, detail);
+            return self.channel.or_poison(&mut ch, Err(violation)).ok();
+        }
 
-        // Finally, construct the region
-        let buf = unsafe { std::slice::from_raw_parts_mut(ptr, nbytes) };
-        Some(MutRegion {
-            owner: self,
-            cur,
-            buf,
-        })
+        ch.outstanding_writes.remove(interval);
+        ch.outstanding_writes.insert(next);
+        if let Some(by) = ch.reserved_by.remove(&interval.beg) {
+            ch.reserved_by.insert(next.beg, by);
+        }
+        ch.writes.end = next.end;
+        // An empty record would be indistinguishable from the next one, so
+        // drop it.
+        if let Some(i) = ch.find_record(interval) {
+            if nbytes == 0 {
+                ch.records.remove(i);
+            } else {
+                ch.records[i].interval = next;
+            }
+        }
+        Some(next)
     }
 
-   pub(super) fn unreserve(&self, interval: &Interval) {
+    pub(crate) fn unreserve(&self, interval: &Interval) {
+        let crc = self.checksum(interval);
         let mut ch = self.channel.inner.lock();
+        self.release(&mut ch, interval, crc);
+    }
+
+    /// The CRC32C of the bytes in `interval`, if the channel stores them.
+    /// Taken before locking: the interval is still ours, so nobody else
+    /// touches those bytes.
+    fn checksum(&self, interval: &Interval) -> Option<u32> {
+        if !self.channel.checksums.load(Ordering::Relaxed) {
+            return None;
+        }
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                self.channel.as_ptr().offset(interval.beg.offset),
+                interval.len() as usize,
+            )
+        };
+        Some(crc32c::crc32c(bytes))
+    }
+
+    /// Commits `interval` as a copy of another record: marked corrupt if
+    /// that was, and with its checksum instead of one of these bytes.
+    pub(crate) fn unreserve_copy(&self, interval: &Interval, corrupt: bool, crc: Option<u32>) {
+        let mut ch = self.channel.inner.lock();
+        if let Some(i) = ch.find_record(interval) {
+            ch.records[i].corrupt = corrupt;
+        }
+        self.release(&mut ch, interval, crc);
+    }
+
+    /// Gives back a region that won't be finished, without publishing what's
+    /// in it.
+    ///
+    /// The region is discarded if it's the most recent reservation.
+    /// Otherwise readers are waiting on it, so the channel is poisoned before
+    /// the region is committed.
+    pub(crate) fn discard(&self, interval: &Interval, why: &str) {
+        if let Some(empty) = self.shrink(interval, 0) {
+            self.unreserve(&empty);
+            return;
+        }
+        let mut ch = self.channel.inner.lock();
+        let detail = format!("{} {}", why, interval);
+        let violation = Violation::new(Invariant::CompleteRecords, detail);
+        let _ = self.channel.or_poison(&mut ch, Err::<(), _>(violation));
+        self.release(&mut ch, interval, None);
+    }
+
+    /// Commits `interval` with checksum `crc` and wakes the readers.
+    fn release(&self, ch: &mut RawChannel, interval: &Interval, crc: Option<u32>) {
+        let committed = Self::commit(ch, interval, crc);
+        let _ = self.channel.or_poison(ch, committed);
+        self.channel.data_available.notify_all();
+        ch.wake_watchers();
+    }
+
+    /// Gives back a region whose writer panicked before finishing it.
+    ///
+    /// The region is discarded if it's the most recent reservation.
+    /// Otherwise readers are waiting on it, so it's committed but its record
+    /// is marked corrupt.
+    ///
+    /// If the channel poisons on panic, it's poisoned before the region is
+    /// committed, so no reader sees the bytes without finding out.
+    pub(crate) fn abandon(&self, interval: &Interval) {
+        warn!("sender panicked, abandoning {}", interval);
+        let empty = self.shrink(interval, 0);
+
+        let mut ch = self.channel.inner.lock();
+        if ch.poison_on_panic {
+            let detail = format!("sender panicked while writing {}", interval);
+            let violation = Violation::new(Invariant::CompleteRecords, detail);
+            let _ = self.channel.or_poison(&mut ch, Err::<(), _>(violation));
+        }
+        let interval = match empty {
+            Some(empty) => empty,
+            None => {
+                if let Some(i) = ch.find_record(interval) {
+                    ch.records[i].corrupt = true;
+                }
+                *interval
+            }
+        };
+        self.release(&mut ch, &interval, None);
+    }
+
+    /// Commits several intervals with a single acquisition of the lock.
+    pub(super) fn unreserve_many<'i>(&self, intervals: impl IntoIterator<Item = &'i Interval>) {
+        let intervals: Vec<_> = intervals
+            .into_iter()
+            .map(|interval| (interval, self.checksum(interval)))
+            .collect();
+        let mut ch = self.channel.inner.lock();
+        for (interval, crc) in intervals {
+            let committed = Self::commit(&mut ch, interval, crc);
+            let _ = self.channel.or_poison(&mut ch, committed);
+        }
+        self.channel.data_available.notify_all();
+        ch.wake_watchers();
+    }
+
+    /// Fails if something is being read past the new read head. The commit
+    /// still goes through.
+    fn commit(ch: &mut RawChannel, interval: &Interval, crc: Option<u32>) -> Result<(), Violation> {
         ch.outstanding_writes.remove(interval);
+        ch.reserved_by.remove(&interval.beg);
+        if let Some(i) = ch.find_record(interval) {
+            let record = &mut ch.records[i];
+            record.committed = Instant::now();
+            if crc.is_some() {
+                record.crc = crc;
+            }
+        }
+        ch.counters.committed += interval.len() as u64;
 
-        let mn = ch.outstanding_writes.iter().min().map(|e| *e);
+        let mn = ch.outstanding_writes.iter().min().copied();
 
         // The outstanding_writes includes the uncommitted writes, so if it's
-        // empty the high_mark should be the last interval removed.
-        ch.writes.beg = mn.map(|e| e.beg).unwrap_or(interval.end.into());
+        // empty everything up to the write head has been committed. That's
+        // not necessarily the end of the last interval removed: regions may
+        // be committed out of order.
+        ch.writes.beg = mn.map(|e| e.beg).unwrap_or(ch.writes.end.into());
 
         // read_head should default to write_tail when there are no
-        // outstanding_writes. But write_tail defaults to interval.end in that
-        // case. Take that shortcut below to avoid switching the sense of the
-        // endpoint.
+        // outstanding_writes. But write_tail defaults to the write head in
+        // that case. Take that shortcut below to avoid switching the sense of
+        // the endpoint.
         let c0 = ch.reads.end.cycle;
         ch.reads.end = mn
             .map(|e| e.beg.to_end(e.high_mark))
-            .unwrap_or(interval.end);
+            .unwrap_or(ch.writes.end);
         let c1 = ch.reads.end.cycle;
+        ch.number_records();
 
-        assert!(
-            BegCursor::from(ch.reads.end)
-                >= *ch.outstanding_reads.max().unwrap_or(&ch.reads.end.into())
-        );
+        let furthest_read = ch.outstanding_reads.max().copied();
 
         // update high mark for when read_head crosses a cycle boundary
         if c1 > c0 {
@@ -168,15 +572,32 @@ impl Sender {
             ch.reads.high_mark = ch.writes.high_mark;
             ch.writes.high_mark = None;
         }
+
+        match furthest_read {
+            Some(r) if BegCursor::from(ch.reads.end) < r => {
+                let detail = format!("outstanding read {} reads:{}", r, ch.reads);
+                Err(Violation::new(Invariant::ReadsCommitted, detail))
+            }
+            _ => Ok(()),
+        }
     }
 }
 
+pub(super) fn collide(w: &EndCursor, r: &BegCursor) -> bool {
+    // On the same cycle, there can be no collision bc enforce
+    // r<=w elsewhere. Otherwise,
+    w.cycle > r.cycle && (w.offset > r.offset || w.cycle > r.cycle + 1)
+    // The w.cycle>r.cycle+1 case handles when the first unread
+    // byte is hanging off the end of the cycle.
+}
+
 #[cfg(test)]
 mod test {
     use std::{
+        panic::{catch_unwind, AssertUnwindSafe},
         sync::{
             atomic::{AtomicBool, AtomicU8, Ordering},
-            Arc,
+            mpsc, Arc,
         },
         thread::{sleep, spawn},
         time::Duration,
@@ -186,8 +607,10 @@ mod test {
 
     use crate::base::{
         channel,
+        channel::ChannelFactory,
         cursor::{BegCursor, EndCursor, Interval},
         region::MutRegion,
+        Error, Invariant, Sender,
     };
 
     #[test]
@@ -244,6 +667,299 @@ mod test {
 
         done.store(true, Ordering::SeqCst);
     }
+
+    #[test]
+    fn regions_committed_out_of_order_are_all_readable() {
+        let (mut tx, mut rx) = channel(16);
+        let mut other = tx.channel().sender();
+        let first = tx.map(3).unwrap();
+        let second = other.map(5).unwrap();
+        drop(second);
+        drop(first);
+        assert_eq!(rx.next().unwrap().len(), 8);
+    }
+
+    #[test]
+    fn a_wrap_longer_than_what_was_read_does_not_wait() {
+        let (mut tx, mut rx) = channel(32);
+        tx.map(20).unwrap();
+        while rx.next().is_some() {}
+        // Ends past offset 20, where the readers stopped in the cycle before.
+        let reg = tx.map(25).unwrap();
+        assert_eq!(reg.cur.beg, BegCursor { cycle: 1, offset: 0 });
+        drop(reg);
+        assert_eq!(rx.next().unwrap().len(), 25);
+    }
+
+    #[test]
+    fn a_wrap_waits_only_until_the_readers_reach_the_end_of_the_cycle() {
+        let (mut tx, mut rx) = channel(32);
+        tx.map(10).unwrap().fill(1);
+        tx.map(10).unwrap().fill(2);
+        assert_eq!(&*rx.next_record().unwrap(), &[1; 10]);
+
+        // Offset 10 of the last cycle is still unread.
+        let mut other = tx.channel().sender();
+        let writer = spawn(move || other.map(25).unwrap().fill(3));
+        while tx.channel.inner.lock().waiting_writers.is_empty() {
+            assert!(!writer.is_finished(), "the wrap didn't wait");
+            sleep(Duration::from_millis(1));
+        }
+
+        // Reading the rest frees the whole cycle.
+        assert_eq!(&*rx.next_record().unwrap(), &[2; 10]);
+        writer.join().unwrap();
+        assert_eq!(&*rx.next_record().unwrap(), &[3; 25]);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn map_many_wraps_within_run() {
+        let (mut tx,mut rx)=channel(10);
+        tx.map(6).unwrap();
+        while rx.next().is_some() {};
+        {
+            let mut batch = tx.map_many(&[3,3]).unwrap();
+            assert_eq!(batch.len(),2);
+            for (buf,v) in batch.iter_mut().zip(1u8..) {
+                buf.fill(v);
+            }
+            let curs:Vec<_>=batch.regions.iter().map(|(cur,_)| *cur).collect();
+            assert_eq!(curs[0],Interval{
+                beg: BegCursor { cycle: 0, offset: 6 },
+                end: EndCursor { cycle: 0, offset: 9 },
+                high_mark: None
+            });
+            assert_eq!(curs[1],Interval{
+                beg: BegCursor { cycle: 1, offset: 0 },
+                end: EndCursor { cycle: 1, offset: 3 },
+                high_mark: Some(9)
+            });
+        }
+
+        assert_eq!(&*rx.next().unwrap(),&[1,1,1]);
+        assert_eq!(&*rx.next().unwrap(),&[2,2,2]);
+        assert!(rx.next().is_none());
+    }
+
+    #[test]
+    fn map_many_commit_individually() {
+        let (mut tx, mut rx) = channel(16);
+        {
+            let mut regions = tx.map_many(&[3, 5]).unwrap().into_regions();
+            let second = regions.pop().unwrap();
+            drop(second);
+            // The first region is still outstanding so nothing is readable.
+            assert!(rx.next().is_none());
+        }
+        assert_eq!(rx.next().unwrap().len(), 8);
+    }
+
+    #[test]
+    fn truncating_a_batch_undoes_its_wrap() {
+        let (mut tx, mut rx) = channel(10);
+        tx.map(6).unwrap();
+        while rx.next().is_some() {}
+        {
+            let mut batch = tx.map_many(&[4, 4]).unwrap();
+            assert!(batch.truncate(3));
+            assert_eq!(batch.len(), 1);
+        }
+        {
+            let c = tx.channel.inner.lock();
+            assert_eq!(c.writes.end, EndCursor { cycle: 0, offset: 9 });
+            assert_eq!(c.writes.high_mark, None);
+        }
+        assert_eq!(rx.next().unwrap().len(), 3);
+        // The next region fits in the space the batch gave back.
+        assert_eq!(tx.map(1).unwrap().cur.end, EndCursor { cycle: 0, offset: 10 });
+    }
+
+    #[test]
+    fn map_many_rejects_run_larger_than_capacity() {
+        let (mut tx, _rx) = channel(10);
+        assert!(tx.map_many(&[6, 6]).is_none());
+        assert!(tx.map(10).is_some());
+    }
+
+    #[test]
+    fn try_map_reports_waits_on_regions_this_thread_holds() {
+        let (mut tx, mut rx) = channel(10);
+        assert_eq!(tx.try_map(11).err(), Some(Error::TooLarge));
+
+        tx.map(6).unwrap();
+        while rx.next().is_some() {}
+        let (held, _) = tx.map_raw(4).unwrap();
+        assert_eq!(tx.try_map(8).err(), Some(Error::WouldDeadlock));
+        assert_eq!(tx.channel().snapshot().writes, 6..10);
+        tx.unreserve(&held);
+
+        // A region another thread reads is waited for.
+        let (acquired, wait) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _region = rx.next().unwrap();
+                acquired.send(()).unwrap();
+                sleep(Duration::from_millis(20));
+            });
+            wait.recv().unwrap();
+            assert_eq!(tx.try_map(8).unwrap().len(), 8);
+        });
+
+        tx.channel().close();
+        assert_eq!(tx.try_map(1).err(), Some(Error::Closed));
+    }
+
+    #[test]
+    fn map_returns_none_rather_than_wait_on_a_region_this_thread_reads() {
+        let (mut tx, mut rx) = channel(10);
+        tx.map(6).unwrap();
+        let region = rx.next().unwrap();
+        assert!(tx.map(6).is_none());
+        assert!(tx.map_many(&[2, 4]).is_none());
+        assert_eq!(tx.channel().snapshot().writes, 6..6);
+        drop(region);
+        assert_eq!(tx.map(6).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn two_senders_on_one_thread_would_deadlock() {
+        let (mut tx, mut rx) = channel(10);
+        let mut other = tx.channel().sender();
+        tx.map(6).unwrap();
+        while rx.next().is_some() {}
+
+        let writing = other.map(4).unwrap();
+        assert_eq!(tx.try_map(8).err(), Some(Error::WouldDeadlock));
+        drop(writing);
+        assert_eq!(rx.next().unwrap().len(), 4);
+        assert_eq!(tx.try_map(8).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn retracting_a_wrap_keeps_the_cycle_a_later_reservation_is_in() {
+        let (mut tx, mut rx) = channel(10);
+        tx.map(8).unwrap();
+        while rx.next().is_some() {}
+
+        // Wraps, starting cycle 1.
+        let (wrapped, _) = tx.map_raw(4).unwrap();
+        let (later, _) = tx.map_raw(2).unwrap();
+        Sender::retract(&mut tx.channel.inner.lock(), &[wrapped]);
+
+        let report = tx.channel().validate();
+        assert!(report.is_ok(), "{}", report);
+        let snapshot = tx.channel().snapshot();
+        assert_eq!(snapshot.writes.end, 14);
+        assert_eq!(snapshot.outstanding_writes, vec![(12..14)]);
+        tx.unreserve(&later);
+    }
+
+    #[test]
+    fn a_region_dropped_in_a_panic_is_not_published() {
+        let (mut tx, mut rx) = channel(10);
+        let mut other = tx.channel().sender();
+        let panicked = catch_unwind(AssertUnwindSafe(|| {
+            tx.map(4).unwrap().fill(1);
+            let mut region = tx.map(4).unwrap();
+            region[0] = 2;
+            panic!("filling the region failed");
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(rx.next_record().unwrap().seq(), 0);
+        assert!(rx.next_record().is_none());
+
+        // When later regions are reserved, readers still get it but know.
+        let panicked = catch_unwind(AssertUnwindSafe(|| {
+            let _region = tx.map(2).unwrap();
+            other.map(2).unwrap().fill(3);
+            panic!("filling the region failed");
+        }));
+        assert!(panicked.is_err());
+        assert!(rx.next_record().unwrap().is_corrupt());
+        assert!(!rx.next_record().unwrap().is_corrupt());
+    }
+
+    #[test]
+    fn a_panic_can_poison_the_channel() {
+        let (mut tx, mut rx) = channel(10);
+        tx.channel().set_poison_on_panic(true);
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _region = tx.map(4).unwrap();
+            panic!("filling the region failed");
+        }));
+        match rx.try_recv() {
+            Err(Error::Poisoned(report)) => {
+                assert_eq!(report.violations[0].invariant, Invariant::CompleteRecords)
+            }
+            _ => panic!("expected the channel to be poisoned"),
+        };
+    }
+
+    #[test]
+    fn closing_on_a_blocked_writer_wakes_draining_readers() {
+        let (mut tx, mut rx) = channel(10);
+        let _lagging = tx.channel().receiver();
+        tx.map(10).unwrap();
+        while rx.next().is_some() {}
+
+        let mut blocked = tx.channel().sender();
+        let writer = spawn(move || blocked.map(4).is_none());
+        while tx.channel.inner.lock().waiting_writers.is_empty() {
+            sleep(Duration::from_millis(1));
+        }
+        let (done, finished) = mpsc::channel();
+        let reader = spawn(move || {
+            let closed = rx.recv().is_none();
+            done.send(()).unwrap();
+            closed
+        });
+        while !tx.channel.inner.lock().receivers.values().any(|r| r.waiting) {
+            sleep(Duration::from_millis(1));
+        }
+
+        // Close without waking the readers: the reservation the writer gives
+        // back is the last thing they're waiting on.
+        {
+            let mut ch = tx.channel.inner.lock();
+            ch.is_accepting_writes = false;
+            ch.wake_writers();
+        }
+        assert!(writer.join().unwrap());
+        finished
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the reader wasn't woken");
+        assert!(reader.join().unwrap());
+    }
+
+    #[test]
+    fn release_wakes_only_the_writer_it_made_room_for() {
+        let (mut tx, mut rx) = channel(10);
+        tx.map(5).unwrap().fill(1);
+        tx.map(5).unwrap().fill(2);
+
+        let waiting = |tx: &super::Sender| tx.channel.inner.lock().waiting_writers.len();
+        let writers: Vec<_> = (0..2)
+            .map(|i| {
+                let mut w = tx.channel().sender();
+                let writer = spawn(move || w.map(5).unwrap().fill(3 + i));
+                while waiting(&tx) <= i as usize {
+                    sleep(Duration::from_millis(1));
+                }
+                writer
+            })
+            .collect();
+
+        let mut writers = writers.into_iter();
+        assert_eq!(&*rx.next_record().unwrap(), &[1; 5]);
+        writers.next().unwrap().join().unwrap();
+        assert_eq!(waiting(&tx), 1);
+
+        assert_eq!(&*rx.next_record().unwrap(), &[2; 5]);
+        writers.next().unwrap().join().unwrap();
+        assert_eq!(&*rx.next_record().unwrap(), &[3; 5]);
+        assert_eq!(&*rx.next_record().unwrap(), &[4; 5]);
+    }
 }
 
 // TODO: test channel drain, outstanding writes etc
@@ -0,0 +1,100 @@
+//! A point in time view of a channel's state.
+
+use std::{fmt::Display, ops::Range};
+
+/// The state of a channel at one point in time. See [`Channel::snapshot`].
+///
+/// Positions count the bytes in the stream since the channel was created.
+/// They don't include the space skipped at the end of the buffer when a
+/// region wraps around.
+///
+/// [`Channel::snapshot`]: super::Channel::snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub capacity: usize,
+    /// True once the channel stopped accepting writes.
+    pub closed: bool,
+    /// From the read tail, the oldest byte a receiver still needs, to the
+    /// end of the committed bytes.
+    pub reads: Range<u64>,
+    /// From the oldest uncommitted byte to the write head.
+    pub writes: Range<u64>,
+    /// The reservations that haven't been committed yet, in stream order.
+    pub outstanding_writes: Vec<Range<u64>>,
+    /// Bytes at the end of the buffer the write head skipped when it
+    /// wrapped, which stay unusable until the read tail wraps too.
+    pub skipped: u64,
+    /// One entry for each live receiver, in the order they were created.
+    pub receivers: Vec<ReceiverSnapshot>,
+    /// One entry for each consumer group, in the order they were created.
+    pub groups: Vec<GroupSnapshot>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReceiverSnapshot {
+    /// See [`Receiver::id`](super::Receiver::id).
+    pub id: u64,
+    /// The end of what the receiver has read so far.
+    pub position: u64,
+    pub state: ReceiverState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupSnapshot {
+    /// See [`ConsumerGroup::id`](super::ConsumerGroup::id).
+    pub id: u64,
+    /// The members have released every record before this.
+    pub released: u64,
+    /// Where the next record will be claimed.
+    pub claimed: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiverState {
+    /// Not reading or waiting.
+    Idle,
+    /// Blocked until more data is committed.
+    Waiting,
+    /// Holding this many regions that haven't been released.
+    Reading(usize),
+}
+
+impl Snapshot {
+    /// Bytes available to writers.
+    pub fn free(&self) -> u64 {
+        self.capacity as u64 - (self.writes.end - self.reads.start) - self.skipped
+    }
+}
+
+impl Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "write:({}-{}{}) read:({}-{}) outstanding writes:{:?} receivers:[",
+            self.writes.start,
+            self.writes.end,
+            if self.closed { " CLOSED" } else { "" },
+            self.reads.start,
+            self.reads.end,
+            self.outstanding_writes,
+        )?;
+        for (i, r) in self.receivers.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}@{}:{:?}", r.id, r.position, r.state)?;
+        }
+        write!(f, "]")?;
+        if !self.groups.is_empty() {
+            write!(f, " groups:[")?;
+            for (i, g) in self.groups.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " ")?;
+                }
+                write!(f, "{}@{}-{}", g.id, g.released, g.claimed)?;
+            }
+            write!(f, "]")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+//! Counters describing how a channel is being used.
+
+use std::time::Duration;
+
+/// A copy of a channel's counters. See [`Channel::stats`].
+///
+/// Byte counts don't include the space skipped at the end of the buffer
+/// when a region wraps around.
+///
+/// [`Channel::stats`]: super::Channel::stats
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Total bytes committed by senders.
+    pub committed: u64,
+    /// Total bytes released by the slowest receiver and so made writable
+    /// again.
+    pub released: u64,
+    /// Bytes between the read tail and the write head: everything that is
+    /// reserved, unread or still being read.
+    pub occupancy: u64,
+    /// The largest `occupancy` seen.
+    pub peak_occupancy: u64,
+    /// Number of times a sender had to wait for space.
+    pub writer_waits: u64,
+    /// Total time senders spent waiting for space.
+    pub writer_wait_time: Duration,
+    /// Number of times a reservation wrapped around the end of the buffer.
+    pub wraps: u64,
+    /// One entry for each live receiver, in the order they were created.
+    pub receivers: Vec<ReceiverStats>,
+    /// One entry for each consumer group, in the order they were created.
+    pub groups: Vec<GroupStats>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReceiverStats {
+    /// See [`Receiver::id`](super::Receiver::id).
+    pub id: u64,
+    /// Committed bytes the receiver hasn't read yet.
+    pub lag: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupStats {
+    /// See [`ConsumerGroup::id`](super::ConsumerGroup::id).
+    pub id: u64,
+    /// Committed bytes no member has claimed yet.
+    pub lag: u64,
+}
+
+/// The counters kept in the channel. The rest of [`Stats`] is computed
+/// from the cursors when asked for.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) committed: u64,
+    pub(crate) released: u64,
+    pub(crate) peak_occupancy: u64,
+    pub(crate) writer_waits: u64,
+    pub(crate) writer_wait_time: Duration,
+    pub(crate) wraps: u64,
+}
@@ -0,0 +1,295 @@
+//! The synchronization primitives the channel is built on.
+//!
+//! Normally these are `parking_lot`'s lock and condition variable and the
+//! standard library's threads and atomics. Built with `--cfg loom` they're
+//! swapped for [loom](https://docs.rs/loom)'s models so the tests below can
+//! check how a few senders and receivers interleave:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib sync::tests
+//! ```
+
+#[cfg(not(loom))]
+pub(crate) use std::{
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, Ordering},
+    thread::{current, yield_now, ThreadId},
+};
+
+#[cfg(not(loom))]
+pub(crate) use parking_lot::{Condvar, Mutex, MutexGuard};
+
+/// Runs `f` with the lock let go, handing it to a waiting thread if there
+/// is one.
+#[cfg(not(loom))]
+pub(crate) fn unlocked_fair<T, U>(guard: &mut MutexGuard<'_, T>, f: impl FnOnce() -> U) -> U {
+    MutexGuard::unlocked_fair(guard, f)
+}
+
+#[cfg(loom)]
+pub(crate) use loom::{
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, Ordering},
+    thread::{current, yield_now, ThreadId},
+};
+
+#[cfg(loom)]
+pub(crate) use self::model::{unlocked_fair, Condvar, Mutex, MutexGuard};
+
+/// Loom's primitives behind `parking_lot`'s interface: no lock poisoning,
+/// and condition variables that wait on a guard in place.
+#[cfg(loom)]
+mod model {
+    use std::{
+        fmt::{self, Debug},
+        ops::{Deref, DerefMut},
+    };
+
+    #[derive(Default)]
+    pub(crate) struct Mutex<T>(loom::sync::Mutex<T>);
+
+    pub(crate) struct MutexGuard<'a, T> {
+        mutex: &'a Mutex<T>,
+        inner: Option<loom::sync::MutexGuard<'a, T>>,
+    }
+
+    #[derive(Default, Debug)]
+    pub(crate) struct Condvar(loom::sync::Condvar);
+
+    impl<T> Mutex<T> {
+        pub(crate) fn new(value: T) -> Self {
+            Mutex(loom::sync::Mutex::new(value))
+        }
+
+        pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+            MutexGuard {
+                mutex: self,
+                inner: Some(self.0.lock().unwrap()),
+            }
+        }
+    }
+
+    impl<T: Debug> Debug for Mutex<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self.0.try_lock() {
+                Ok(value) => f.debug_tuple("Mutex").field(&*value).finish(),
+                Err(_) => f.write_str("Mutex(<locked>)"),
+            }
+        }
+    }
+
+    impl<T> Deref for MutexGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            self.inner.as_ref().unwrap()
+        }
+    }
+
+    impl<T> DerefMut for MutexGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            self.inner.as_mut().unwrap()
+        }
+    }
+
+    impl Condvar {
+        pub(crate) fn new() -> Self {
+            Condvar(loom::sync::Condvar::new())
+        }
+
+        pub(crate) fn wait<T>(&self, guard: &mut MutexGuard<'_, T>) {
+            let inner = guard.inner.take().unwrap();
+            guard.inner = Some(self.0.wait(inner).unwrap());
+        }
+
+        pub(crate) fn notify_one(&self) {
+            self.0.notify_one();
+        }
+
+        pub(crate) fn notify_all(&self) {
+            self.0.notify_all();
+        }
+    }
+
+    pub(crate) fn unlocked_fair<T, U>(guard: &mut MutexGuard<'_, T>, f: impl FnOnce() -> U) -> U {
+        drop(guard.inner.take());
+        let out = f();
+        guard.inner = Some(guard.mutex.0.lock().unwrap());
+        out
+    }
+}
+
+#[cfg(all(test, loom))]
+mod tests {
+    use std::sync::Arc;
+
+    use loom::{model::Builder, thread::spawn};
+
+    use crate::base::{Channel, ChannelFactory, Receiver};
+
+    /// Checks every interleaving of `f`.
+    fn model(f: impl Fn() + Sync + Send + 'static) {
+        Builder::new().check(f);
+    }
+
+    #[test]
+    fn two_writers_two_readers() {
+        model(|| {
+            let ch = Arc::new(Channel::new(4));
+            let read = |mut rx: Receiver| {
+                let mut seen = Vec::new();
+                while seen.len() < 4 {
+                    seen.extend_from_slice(&rx.recv().unwrap());
+                }
+                seen.sort();
+                assert_eq!(seen, [1, 1, 2, 2]);
+            };
+            // The main thread is the second writer and the second reader,
+            // which keeps the model small enough to check every interleaving.
+            let (mut tx, local) = (ch.sender(), ch.receiver());
+            let reader = {
+                let rx = ch.receiver();
+                spawn(move || read(rx))
+            };
+            let writer = spawn(move || tx.map(2).unwrap().fill(1));
+            ch.sender().map(2).unwrap().fill(2);
+            read(local);
+            writer.join().unwrap();
+            reader.join().unwrap();
+            let report = ch.validate();
+            assert!(report.is_ok(), "{}", report);
+        });
+    }
+
+    #[test]
+    fn close_races_with_map_and_next() {
+        model(|| {
+            let ch = Arc::new(Channel::new(4));
+            let mut tx = ch.sender();
+            let mut rx = ch.receiver();
+            let writer = spawn(move || {
+                let mut sent = 0;
+                while let Some(mut region) = tx.map(2) {
+                    region.fill(7);
+                    sent += 2;
+                    if sent == 6 {
+                        break;
+                    }
+                }
+                sent
+            });
+            let reader = spawn(move || {
+                let mut got = rx.next().map_or(0, |region| region.len());
+                while let Some(region) = rx.recv() {
+                    assert!(region.iter().all(|&b| b == 7));
+                    got += region.len();
+                }
+                got
+            });
+            ch.close();
+            let sent = writer.join().unwrap();
+            assert_eq!(reader.join().unwrap(), sent);
+            let report = ch.validate();
+            assert!(report.is_ok(), "{}", report);
+        });
+    }
+
+    #[test]
+    fn out_of_order_commits_across_a_wrap() {
+        model(|| {
+            let ch = Arc::new(Channel::new(8));
+            let (a, b) = (ch.sender(), ch.sender());
+            let mut rx = ch.receiver();
+            // Move the head near the end, so the second region wraps.
+            let (prefix, _) = a.map_raw(6).unwrap();
+            a.unreserve(&prefix);
+            drop(rx.next());
+            let (first, _) = a.map_raw(2).unwrap();
+            let (second, _) = b.map_raw(3).unwrap();
+
+            let writers = [
+                spawn(move || a.unreserve(&first)),
+                spawn(move || b.unreserve(&second)),
+            ];
+            let reader = spawn(move || {
+                let mut got = 0;
+                while let Some(region) = rx.recv() {
+                    got += region.len();
+                }
+                got
+            });
+            for w in writers {
+                w.join().unwrap();
+            }
+            ch.close();
+            assert_eq!(reader.join().unwrap(), 5);
+            let report = ch.validate();
+            assert!(report.is_ok(), "{}", report);
+        });
+    }
+
+    #[test]
+    fn truncate_races_with_a_later_reservation() {
+        model(|| {
+            let ch = Arc::new(Channel::new(4));
+            let (mut a, mut b) = (ch.sender(), ch.sender());
+            let mut rx = ch.receiver();
+            // The first region below wraps, so truncating it to nothing
+            // undoes the wrap.
+            a.map(3).unwrap();
+            drop(rx.next());
+
+            let truncating = spawn(move || {
+                let mut region = a.map(2).unwrap();
+                region.fill(1);
+                if region.truncate(0) {
+                    0
+                } else {
+                    2
+                }
+            });
+            let other = spawn(move || b.map(1).unwrap().fill(2));
+            let reader = spawn(move || {
+                let mut seen = Vec::new();
+                while let Some(region) = rx.recv() {
+                    seen.extend_from_slice(&region);
+                }
+                seen
+            });
+            let kept = truncating.join().unwrap();
+            other.join().unwrap();
+            ch.close();
+            let mut seen = reader.join().unwrap();
+            seen.sort();
+            assert_eq!(seen, [&[1, 1][..kept], &[2]].concat());
+            let report = ch.validate();
+            assert!(report.is_ok(), "{}", report);
+        });
+    }
+
+    #[test]
+    fn a_held_region_holds_back_a_wrap() {
+        model(|| {
+            let ch = Arc::new(Channel::new(4));
+            let mut tx = ch.sender();
+            let mut rx = ch.receiver();
+            let writer = spawn(move || {
+                for i in 1..=3u8 {
+                    tx.map(2).unwrap().fill(i);
+                }
+            });
+            let reader = spawn(move || {
+                let mut seen = Vec::new();
+                while let Some(region) = rx.recv() {
+                    seen.extend_from_slice(&region);
+                }
+                seen
+            });
+            writer.join().unwrap();
+            ch.close();
+            assert_eq!(reader.join().unwrap(), [1, 1, 2, 2, 3, 3]);
+            let report = ch.validate();
+            assert!(report.is_ok(), "{}", report);
+        });
+    }
+}
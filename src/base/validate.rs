@@ -0,0 +1,180 @@
+//! Checking a channel's invariants.
+
+use std::fmt::Display;
+
+use super::{channel::RawChannel, cursor::EndCursor};
+
+/// The rules a channel's cursors have to follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Invariant {
+    /// Every cursor falls in a cycle the channel knows the position of.
+    KnownCycles,
+    /// The read high mark is set exactly when the readable bytes wrap.
+    ReadHighMark,
+    /// The read tail is at or before the read head, which is at or before
+    /// the write head. The write tail is at or before the write head.
+    CursorOrder,
+    /// Receivers read between the read tail and the read head.
+    ReceiverInReads,
+    /// Nothing is being read past the read head.
+    ReadsCommitted,
+    /// Each readable byte belongs to a record.
+    Records,
+    /// A reservation starts no more than one cycle after the read tail.
+    ReservationInReach,
+    /// Senders finish the regions they commit. Only broken by a sender
+    /// that panicked, and only checked when the channel was asked to
+    /// [poison on panic](super::Channel::set_poison_on_panic).
+    CompleteRecords,
+}
+
+/// An invariant that didn't hold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub invariant: Invariant,
+    /// The cursors involved.
+    pub detail: String,
+}
+
+/// What [`Channel::validate`](super::Channel::validate) found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub violations: Vec<Violation>,
+    /// A dump of the channel's state when the report was made.
+    pub state: String,
+}
+
+impl Violation {
+    pub(crate) fn new(invariant: Invariant, detail: impl Into<String>) -> Self {
+        Violation {
+            invariant,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for v in &self.violations {
+            writeln!(f, "{:?}: {}", v.invariant, v.detail)?;
+        }
+        write!(f, "state: {}", self.state)
+    }
+}
+
+impl RawChannel {
+    /// Every invariant that doesn't hold right now.
+    pub(crate) fn violations(&self) -> Vec<Violation> {
+        let outstanding_read = self.outstanding_reads.max().copied();
+        let known = self.is_consistent()
+            && outstanding_read.is_none_or(|r| self.cycle_bases.contains_key(&r.cycle));
+        if !known {
+            let detail = format!("cycles:{:?} reads:{}", self.cycle_bases, self.reads);
+            return vec![Violation::new(Invariant::KnownCycles, detail)];
+        }
+
+        let mut found = Vec::new();
+        let mut check = |ok: bool, invariant: Invariant, detail: &dyn Fn() -> String| {
+            if !ok {
+                found.push(Violation::new(invariant, detail()));
+            }
+        };
+        let pos = |cur: EndCursor| self.position(cur);
+        let (read_tail, read_head) = (pos(self.reads.beg.into()), pos(self.reads.end));
+        let (write_tail, write_head) = (pos(self.writes.beg.into()), pos(self.writes.end));
+
+        check(
+            self.read_high_mark_holds(),
+            Invariant::ReadHighMark,
+            &|| format!("reads:{}", self.reads),
+        );
+        check(
+            read_tail <= read_head && read_head <= write_head && write_tail <= write_head,
+            Invariant::CursorOrder,
+            &|| format!("reads:{} writes:{}", self.reads, self.writes),
+        );
+        for (id, r) in &self.receivers {
+            let p = pos(r.cur);
+            check(
+                read_tail <= p && p <= read_head,
+                Invariant::ReceiverInReads,
+                &|| format!("receiver {} at {} reads:{}", id, r.cur, self.reads),
+            );
+        }
+        if let Some(r) = outstanding_read {
+            check(
+                pos(r.into()) <= read_head,
+                Invariant::ReadsCommitted,
+                &|| format!("outstanding read {} reads:{}", r, self.reads),
+            );
+        }
+        found
+    }
+
+    /// The read high mark is only needed while the readable bytes wrap.
+    pub(crate) fn read_high_mark_holds(&self) -> bool {
+        match self.reads.high_mark {
+            Some(_) => self.reads.end.cycle == self.reads.beg.cycle + 1,
+            None => self.reads.end.cycle == self.reads.beg.cycle,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread::spawn};
+
+    use crate::base::{Channel, ChannelFactory, Error, Invariant};
+
+    #[test]
+    fn validate_holds_while_the_channel_is_busy() {
+        let ch = Arc::new(Channel::new(100));
+        let mut rx = ch.receiver();
+        let writers: Vec<_> = (0..3)
+            .map(|i| {
+                let mut tx = ch.sender();
+                spawn(move || {
+                    for n in 0..500 {
+                        tx.map(1 + (n + i) % 13).unwrap().fill(1);
+                    }
+                })
+            })
+            .collect();
+        let total: usize = (0..3)
+            .flat_map(|i| (0..500).map(move |n| 1 + (n + i) % 13))
+            .sum();
+        let mut bytes = 0;
+        while bytes < total {
+            bytes += rx.recv().unwrap().len();
+            let report = ch.validate();
+            assert!(report.is_ok(), "{}", report);
+        }
+        for w in writers {
+            w.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn a_violation_poisons_the_channel() {
+        let ch = Arc::new(Channel::new(10));
+        let mut tx = ch.sender();
+        let mut rx = ch.receiver();
+        tx.map(4).unwrap();
+        ch.inner.lock().reads.high_mark = Some(8);
+
+        let report = ch.validate();
+        assert_eq!(report.violations[0].invariant, Invariant::ReadHighMark);
+        match tx.try_map(1) {
+            Err(Error::Poisoned(poisoned)) => assert_eq!(*poisoned, report),
+            _ => panic!("expected the channel to be poisoned"),
+        }
+        assert!(matches!(rx.try_recv(), Err(Error::Poisoned(_))));
+        assert!(rx.recv().is_none());
+    }
+}
@@ -0,0 +1,93 @@
+//! How blocked senders and receivers wait.
+
+use super::{
+    channel::RawChannel,
+    sync::{spin_loop, unlocked_fair, yield_now, Condvar, MutexGuard},
+};
+
+/// How a channel's senders and receivers wait when they have to block.
+///
+/// Set with [`Channel::set_wait_strategy`](super::Channel::set_wait_strategy).
+/// Spinning keeps the latency of a wakeup down at the cost of a busy core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WaitStrategy {
+    /// Spin until the wait is over.
+    BusySpin,
+    /// Spin `spins` times, then yield to the scheduler between checks.
+    SpinYield { spins: u32 },
+    /// Spin `spins` times, then sleep until woken.
+    SpinPark { spins: u32 },
+    /// Sleep until woken.
+    #[default]
+    Park,
+}
+
+/// One blocking call's progress through its channel's [`WaitStrategy`].
+pub(crate) struct Backoff {
+    strategy: WaitStrategy,
+    step: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new(ch: &RawChannel) -> Self {
+        Backoff {
+            strategy: ch.wait_strategy,
+            step: 0,
+        }
+    }
+
+    /// Waits once. The caller checks whether it can go on afterwards.
+    ///
+    /// Spinning and yielding let go of the channel lock while they run.
+    /// Parking waits on `condvar`, so the caller needs to be somewhere it
+    /// gets notified.
+    pub(crate) fn wait(&mut self, ch: &mut MutexGuard<'_, RawChannel>, condvar: &Condvar) {
+        let spinning = match self.strategy {
+            WaitStrategy::BusySpin => true,
+            WaitStrategy::SpinYield { spins } | WaitStrategy::SpinPark { spins } => {
+                self.step < spins
+            }
+            WaitStrategy::Park => false,
+        };
+        match self.strategy {
+            _ if spinning => unlocked_fair(ch, spin_loop),
+            WaitStrategy::SpinYield { .. } => unlocked_fair(ch, yield_now),
+            _ => condvar.wait(ch),
+        }
+        self.step = self.step.saturating_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::spawn;
+
+    use super::WaitStrategy;
+    use crate::base::channel;
+
+    #[test]
+    fn every_strategy_moves_the_data() {
+        for strategy in [
+            WaitStrategy::BusySpin,
+            WaitStrategy::SpinYield { spins: 10 },
+            WaitStrategy::SpinPark { spins: 10 },
+            WaitStrategy::Park,
+        ] {
+            let (mut tx, mut rx) = channel(64);
+            tx.channel().set_wait_strategy(strategy);
+            let writer = spawn(move || {
+                for i in 0..100 {
+                    tx.map(5).unwrap().fill(i as u8);
+                }
+                tx.channel().close();
+            });
+            let mut count = 0;
+            while let Some(record) = rx.recv_record() {
+                assert_eq!(&*record, &[count as u8; 5]);
+                count += 1;
+            }
+            writer.join().unwrap();
+            assert_eq!(count, 100, "{:?}", strategy);
+        }
+    }
+}
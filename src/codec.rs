@@ -0,0 +1,358 @@
+//! Compressing records on their way from one channel to another.
+//!
+//! A stage reads each record from a receiver, runs it through a [`Codec`]
+//! and writes the result into a region mapped from a sender. The region is
+//! mapped for the worst case and truncated to what the codec wrote.
+//!
+//! ```no_run
+//! # use std::thread::spawn;
+//! # use gyoll::{base::channel, codec::{encode_stage, DeltaRle16}};
+//! let (mut camera, mut frames) = channel(1 << 24);
+//! let (mut packed, mut to_disk) = channel(1 << 24);
+//! spawn(move || encode_stage(&mut DeltaRle16, &mut frames, &mut packed));
+//! ```
+
+use std::io;
+
+use crate::base::{MutRegion, Receiver, Sender};
+use crate::io::map_failed;
+
+/// Turns a record into another, e.g. compresses it.
+///
+/// `encode` and `decode` write into a region mapped for at least
+/// [`max_encoded_len`](Self::max_encoded_len) or
+/// [`decoded_len`](Self::decoded_len) bytes, and return how many bytes they
+/// wrote. The inputs are usually a [`Region`](crate::base::Region) or a
+/// [`Record`](crate::base::Record).
+pub trait Codec {
+    /// The most bytes `encode` can produce from `len` bytes.
+    fn max_encoded_len(&self, len: usize) -> usize;
+
+    fn encode(&mut self, input: &[u8], out: &mut MutRegion<'_>) -> io::Result<usize>;
+
+    /// The number of bytes `decode` will produce from `input`.
+    fn decoded_len(&self, input: &[u8]) -> io::Result<usize>;
+
+    fn decode(&mut self, input: &[u8], out: &mut MutRegion<'_>) -> io::Result<usize>;
+}
+
+/// Encodes every record `rx` reads into `tx`, one record for each, until
+/// the channel is closed and drained. Then closes `tx`'s channel.
+///
+/// Records keep their [`Meta`](crate::base::Meta). `tx` has to be the only
+/// sender on its channel so its regions can be truncated. Returns the number
+/// of bytes written.
+///
+/// Fails if `rx`'s channel is poisoned, a record doesn't match its
+/// [checksum](crate::base::Record::verify), or the codec fails. Nothing is
+/// published for the record that failed.
+pub fn encode_stage<C: Codec>(
+    codec: &mut C,
+    rx: &mut Receiver,
+    tx: &mut Sender,
+) -> io::Result<u64> {
+    let size = |codec: &C, input: &[u8]| Ok(codec.max_encoded_len(input.len()));
+    let result = stage(codec, rx, tx, size, C::encode);
+    tx.channel().close();
+    result
+}
+
+/// Like [`encode_stage`], decoding instead.
+pub fn decode_stage<C: Codec>(
+    codec: &mut C,
+    rx: &mut Receiver,
+    tx: &mut Sender,
+) -> io::Result<u64> {
+    let result = stage(codec, rx, tx, C::decoded_len, C::decode);
+    tx.channel().close();
+    result
+}
+
+/// Maps a region of `size` bytes for each record and fills it with `code`.
+fn stage<C: Codec>(
+    codec: &mut C,
+    rx: &mut Receiver,
+    tx: &mut Sender,
+    size: impl Fn(&C, &[u8]) -> io::Result<usize>,
+    code: impl Fn(&mut C, &[u8], &mut MutRegion<'_>) -> io::Result<usize>,
+) -> io::Result<u64> {
+    let mut total = 0u64;
+    while let Some(record) = rx.try_recv_record().map_err(io::Error::other)? {
+        let nbytes = size(codec, &record)?;
+        let mut region = tx
+            .try_map_with_meta(nbytes, *record.meta())
+            .map_err(|e| map_failed(e, "output channel closed"))?;
+        let written = match code(codec, &record, &mut region) {
+            Ok(written) if region.truncate(written) => written,
+            Ok(_) => {
+                region.discard("codec stage: another sender mapped the output channel");
+                return Err(io::Error::other("another sender mapped the output channel"));
+            }
+            Err(e) => {
+                region.discard("codec stage: the codec failed");
+                return Err(e);
+            }
+        };
+        total += written as u64;
+    }
+    Ok(total)
+}
+
+/// Lossless compression for 16 bit images, with no dependencies.
+///
+/// Samples are little endian. Each is replaced by its difference to the one
+/// before, which is small and often repeats in smooth images, and repeated
+/// differences are run-length encoded:
+///
+/// ```text
+/// encoded: len:u64 token* [last byte if len is odd]
+/// token:   n:u8 < 128   delta:u16            (the delta n + 1 times)
+///          n:u8 >= 128  delta:u16 * (n - 127) (literal deltas)
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DeltaRle16;
+
+/// Most deltas in one token.
+const MAX_TOKEN: usize = 128;
+const HEADER: usize = 8;
+
+impl DeltaRle16 {
+    fn delta(samples: &[u16], i: usize) -> u16 {
+        match i {
+            0 => samples[0],
+            _ => samples[i].wrapping_sub(samples[i - 1]),
+        }
+    }
+}
+
+impl Codec for DeltaRle16 {
+    fn max_encoded_len(&self, len: usize) -> usize {
+        // All literals: one token byte per MAX_TOKEN deltas.
+        let n = len / 2;
+        HEADER + 2 * n + n.div_ceil(MAX_TOKEN) + len % 2
+    }
+
+    fn encode(&mut self, input: &[u8], out: &mut MutRegion<'_>) -> io::Result<usize> {
+        let need = self.max_encoded_len(input.len());
+        if out.len() < need {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} byte output can't hold {} bytes", out.len(), need),
+            ));
+        }
+        let samples: Vec<u16> = input
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        let n = samples.len();
+        let delta = |i| Self::delta(&samples, i);
+
+        out[..HEADER].copy_from_slice(&(input.len() as u64).to_le_bytes());
+        let mut at = HEADER;
+        let mut put = |out: &mut [u8], bytes: &[u8]| {
+            out[at..at + bytes.len()].copy_from_slice(bytes);
+            at += bytes.len();
+        };
+        let mut i = 0;
+        while i < n {
+            let d = delta(i);
+            let mut run = 1;
+            while i + run < n && run < MAX_TOKEN && delta(i + run) == d {
+                run += 1;
+            }
+            if run > 1 {
+                put(out, &[(run - 1) as u8]);
+                put(out, &d.to_le_bytes());
+                i += run;
+                continue;
+            }
+            // Literals up to where the next run starts.
+            let start = i;
+            while i < n && i - start < MAX_TOKEN && !(i + 1 < n && delta(i + 1) == delta(i)) {
+                i += 1;
+            }
+            put(out, &[(127 + i - start) as u8]);
+            for j in start..i {
+                put(out, &delta(j).to_le_bytes());
+            }
+        }
+        if let Some(&last) = input.chunks_exact(2).remainder().first() {
+            put(out, &[last]);
+        }
+        Ok(at)
+    }
+
+    fn decoded_len(&self, input: &[u8]) -> io::Result<usize> {
+        match input.get(..HEADER) {
+            Some(header) => Ok(u64::from_le_bytes(header.try_into().unwrap()) as usize),
+            None => Err(truncated()),
+        }
+    }
+
+    fn decode(&mut self, input: &[u8], out: &mut MutRegion<'_>) -> io::Result<usize> {
+        let len = self.decoded_len(input)?;
+        if out.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} byte output can't hold {} bytes", out.len(), len),
+            ));
+        }
+        let mut tokens = &input[HEADER..];
+        let mut take = |n: usize| -> io::Result<&[u8]> {
+            let (head, rest) = tokens.split_at_checked(n).ok_or_else(truncated)?;
+            tokens = rest;
+            Ok(head)
+        };
+        let (mut i, mut sample) = (0, 0u16);
+        let mut emit = |out: &mut [u8], i: &mut usize, d: &[u8]| -> io::Result<()> {
+            if *i + 2 > len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "too many samples",
+                ));
+            }
+            sample = sample.wrapping_add(u16::from_le_bytes([d[0], d[1]]));
+            out[*i..*i + 2].copy_from_slice(&sample.to_le_bytes());
+            *i += 2;
+            Ok(())
+        };
+        while i + 2 <= len {
+            let token = take(1)?[0] as usize;
+            if token < 128 {
+                let d = take(2)?;
+                for _ in 0..=token {
+                    emit(out, &mut i, d)?;
+                }
+            } else {
+                for d in take(2 * (token - 127))?.chunks_exact(2) {
+                    emit(out, &mut i, d)?;
+                }
+            }
+        }
+        if len % 2 == 1 {
+            out[len - 1] = take(1)?[0];
+        }
+        Ok(len)
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "encoded record is truncated")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::Arc, thread::spawn};
+
+    use super::{decode_stage, encode_stage, Codec, DeltaRle16};
+    use crate::base::{channel, Channel, ChannelFactory, Meta};
+
+    /// Runs `input` through the codec and back, and returns the encoded
+    /// length.
+    fn round_trip(input: &[u8]) -> usize {
+        let (mut tx, _rx) = channel(1 << 16);
+        let mut codec = DeltaRle16;
+        let mut region = tx.map(codec.max_encoded_len(input.len())).unwrap();
+        let n = codec.encode(input, &mut region).unwrap();
+        let encoded = region[..n].to_vec();
+        drop(region);
+
+        let mut region = tx.map(codec.decoded_len(&encoded).unwrap()).unwrap();
+        assert_eq!(codec.decode(&encoded, &mut region).unwrap(), input.len());
+        assert_eq!(&region[..], input);
+        n
+    }
+
+    #[test]
+    fn delta_rle_round_trips() {
+        // A smooth gradient with a flat patch compresses well.
+        let image: Vec<u8> = (0..4096u16)
+            .map(|i| {
+                if (1000..3000).contains(&i) {
+                    500
+                } else {
+                    i * 3
+                }
+            })
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        assert!(round_trip(&image) < image.len() / 10);
+
+        // Noise doesn't, but still decodes.
+        let noise: Vec<u8> = (0..999u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        round_trip(&noise);
+        round_trip(&[]);
+        round_trip(&[7]);
+        round_trip(&[0xff, 0xff, 0, 0, 0xff, 0xff]);
+
+        let mut codec = DeltaRle16;
+        let (mut tx, _rx) = channel(64);
+        let mut region = tx.map(64).unwrap();
+        assert!(codec
+            .decode(&[4, 0, 0, 0, 0, 0, 0, 0, 0], &mut region)
+            .is_err());
+        assert!(codec.decode(&[4, 0, 0], &mut region).is_err());
+    }
+
+    #[test]
+    fn stages_compress_and_restore_records() {
+        let raw = Arc::new(Channel::new(1 << 14));
+        let packed = Arc::new(Channel::new(1 << 12));
+        let restored = Arc::new(Channel::new(1 << 14));
+        let encoder = {
+            let (mut rx, mut tx) = (raw.receiver(), packed.sender());
+            spawn(move || encode_stage(&mut DeltaRle16, &mut rx, &mut tx).unwrap())
+        };
+        let decoder = {
+            let (mut rx, mut tx) = (packed.receiver(), restored.sender());
+            spawn(move || decode_stage(&mut DeltaRle16, &mut rx, &mut tx).unwrap())
+        };
+        let frame = |i: u16| -> Vec<u8> {
+            (0..1000u16)
+                .flat_map(|x| (x / 100 + i).to_le_bytes())
+                .collect()
+        };
+
+        let mut out = restored.receiver();
+        let mut tx = raw.sender();
+        let producer = spawn(move || {
+            for i in 0..50 {
+                let meta = Meta {
+                    frame: i as u64,
+                    ..Meta::default()
+                };
+                tx.map_with_meta(2000, meta)
+                    .unwrap()
+                    .copy_from_slice(&frame(i));
+            }
+            tx.channel().close();
+        });
+
+        let mut count = 0;
+        while let Some(record) = out.recv_record() {
+            assert_eq!(record.meta().frame, count as u64);
+            assert_eq!(&*record, &frame(count)[..]);
+            count += 1;
+        }
+        assert_eq!(count, 50);
+        producer.join().unwrap();
+        assert!(encoder.join().unwrap() < 50 * 2000 / 5);
+        assert_eq!(decoder.join().unwrap(), 50 * 2000);
+    }
+
+    #[test]
+    fn a_record_that_fails_to_decode_publishes_nothing() {
+        let (mut tx, mut rx) = channel(64);
+        let output = Arc::new(Channel::new(64));
+        let mut out = output.receiver();
+        // Says it holds 4 bytes but has no tokens.
+        tx.map(8).unwrap().copy_from_slice(&4u64.to_le_bytes());
+        tx.channel().close();
+
+        let error = decode_stage(&mut DeltaRle16, &mut rx, &mut output.sender()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(out.try_recv().unwrap().is_none());
+    }
+}
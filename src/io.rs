@@ -0,0 +1,30 @@
+//! Adapters between channels and `std::io`.
+
+pub mod bridge;
+#[cfg(target_os = "linux")]
+mod direct;
+mod reader;
+pub mod replay;
+#[cfg(target_os = "linux")]
+pub mod shm;
+mod source;
+mod writer;
+
+#[cfg(target_os = "linux")]
+pub use direct::{DirectSink, SinkReport};
+pub use reader::ReceiverReader;
+pub use source::Source;
+pub use writer::SenderWriter;
+
+use std::io;
+
+use crate::base::Error;
+
+/// Why a region couldn't be mapped, as an io error. A closed channel is a
+/// broken pipe described by `closed`.
+pub(crate) fn map_failed(error: Error, closed: &str) -> io::Error {
+    match error {
+        Error::Closed => io::Error::new(io::ErrorKind::BrokenPipe, closed),
+        error => io::Error::other(error),
+    }
+}
@@ -0,0 +1,254 @@
+//! Forwards a channel to another host over a byte stream such as TCP.
+//!
+//! The wire format is a short handshake followed by length prefixed frames:
+//!
+//! ```text
+//! handshake: b"gyoll" version:u8 record_size:u64
+//! frame:     len:u64 bytes[len]
+//! end:       u64::MAX
+//! ```
+//!
+//! All integers are little endian. A `record_size` of zero means the stream
+//! isn't framed.
+//!
+//! Both ends use blocking I/O, so backpressure carries across: when the
+//! remote channel is full its side stops reading the socket, the local side
+//! blocks writing to it and stops releasing regions, and eventually local
+//! senders block too.
+
+use std::{
+    io::{self, IoSlice, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use log::debug;
+
+use crate::base::{Receiver, Sender};
+use crate::io::map_failed;
+
+const MAGIC: &[u8; 5] = b"gyoll";
+const VERSION: u8 = 1;
+const END_OF_STREAM: u64 = u64::MAX;
+
+/// How the bytes in a channel are grouped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// No boundaries. The remote side maps regions as large as it can.
+    Stream,
+    /// Every record in the channel is exactly this many bytes. Each one is
+    /// mapped as a separate region on the remote side.
+    Fixed(usize),
+}
+
+impl Framing {
+    fn record_size(&self) -> u64 {
+        match self {
+            Framing::Stream => 0,
+            Framing::Fixed(n) => *n as u64,
+        }
+    }
+}
+
+/// Sends everything `rx` reads to `out` until the channel is closed and
+/// drained, then tells the other side the stream has ended. If the channel
+/// is poisoned, fails without saying so.
+///
+/// Returns the number of payload bytes sent.
+pub fn forward<W: Write>(rx: &mut Receiver, mut out: W, framing: Framing) -> io::Result<u64> {
+    let mut handshake = [0u8; 14];
+    handshake[..5].copy_from_slice(MAGIC);
+    handshake[5] = VERSION;
+    handshake[6..].copy_from_slice(&framing.record_size().to_le_bytes());
+    out.write_all(&handshake)?;
+
+    let mut total = 0u64;
+    while let Some(region) = rx.try_recv().map_err(io::Error::other)? {
+        if let Framing::Fixed(n) = framing {
+            // Records never straddle the wrap, so a region always holds a
+            // whole number of them.
+            if region.len() % n != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "region of {} bytes isn't made of {} byte records",
+                        region.len(),
+                        n
+                    ),
+                ));
+            }
+        }
+        let len = (region.len() as u64).to_le_bytes();
+        write_all_vectored(&mut out, &mut [IoSlice::new(&len), IoSlice::new(&region)])?;
+        total += region.len() as u64;
+    }
+    out.write_all(&END_OF_STREAM.to_le_bytes())?;
+    out.flush()?;
+    debug!("bridge: forwarded {} bytes", total);
+    Ok(total)
+}
+
+/// Accepts a single connection on `listener` and feeds it into `tx`.
+pub fn accept(listener: &TcpListener, tx: &mut Sender) -> io::Result<u64> {
+    let (stream, peer) = listener.accept()?;
+    debug!("bridge: accepted {}", peer);
+    feed(stream, tx)
+}
+
+/// Copies frames sent by [`forward`] from `input` into `tx`.
+///
+/// Closes the channel when the stream ends, whether or not the other side
+/// said goodbye. A region cut short by the end of the stream is discarded,
+/// or poisons the channel if another sender mapped after it. Returns the
+/// number of payload bytes received.
+pub fn feed<R: Read>(mut input: R, tx: &mut Sender) -> io::Result<u64> {
+    let result = feed_frames(&mut input, tx);
+    tx.channel().close();
+    result
+}
+
+fn feed_frames<R: Read>(input: &mut R, tx: &mut Sender) -> io::Result<u64> {
+    let mut handshake = [0u8; 14];
+    input.read_exact(&mut handshake)?;
+    if &handshake[..5] != MAGIC || handshake[5] != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a gyoll bridge stream",
+        ));
+    }
+    let record_size = u64::from_le_bytes(handshake[6..].try_into().unwrap()) as usize;
+    let capacity = tx.channel().capacity();
+    if record_size > capacity {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} byte records don't fit in the channel", record_size),
+        ));
+    }
+    // Without records, split frames so that several regions can be in the
+    // channel at once. Otherwise every frame would wait for an empty ring.
+    let chunk = if record_size > 0 {
+        record_size
+    } else {
+        (capacity / 4).max(1)
+    };
+
+    let mut total = 0u64;
+    loop {
+        let mut len = [0u8; 8];
+        input.read_exact(&mut len)?;
+        let mut remaining = match u64::from_le_bytes(len) {
+            END_OF_STREAM => break,
+            len => len as usize,
+        };
+        while remaining > 0 {
+            let n = remaining.min(chunk);
+            let mut region = tx
+                .try_map(n)
+                .map_err(|e| map_failed(e, "remote channel closed"))?;
+            if let Err(e) = input.read_exact(&mut region) {
+                // The connection dropped part way through. What did arrive
+                // isn't a whole region.
+                region.discard("bridge: the stream ended part way through a frame");
+                return Err(e);
+            }
+            remaining -= n;
+            total += n as u64;
+        }
+    }
+    debug!("bridge: received {} bytes", total);
+    Ok(total)
+}
+
+/// Connects to `addr` and forwards `rx` to it. See [`forward`].
+pub fn connect(
+    rx: &mut Receiver,
+    addr: impl std::net::ToSocketAddrs,
+    framing: Framing,
+) -> io::Result<u64> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    forward(rx, stream, framing)
+}
+
+fn write_all_vectored<W: Write>(out: &mut W, mut bufs: &mut [IoSlice]) -> io::Result<()> {
+    while !bufs.is_empty() {
+        match out.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::Arc, thread::spawn};
+
+    use super::{accept, connect, feed, Framing, MAGIC, VERSION};
+    use crate::base::{channel, Channel, ChannelFactory};
+
+    #[test]
+    fn loopback_preserves_records_and_close() {
+        const RECORD: usize = 100;
+        const COUNT: usize = 500;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The remote channel is much smaller than what's sent, so this only
+        // finishes if backpressure works.
+        let remote = Arc::new(Channel::new(4 * RECORD));
+        let mut remote_rx = remote.receiver();
+        let server = {
+            let mut tx = remote.sender();
+            spawn(move || accept(&listener, &mut tx).unwrap())
+        };
+
+        let local = Arc::new(Channel::new(16 * RECORD));
+        let mut local_rx = local.receiver();
+        let client = spawn(move || connect(&mut local_rx, addr, Framing::Fixed(RECORD)).unwrap());
+        let producer = {
+            let mut tx = local.sender();
+            spawn(move || {
+                for i in 0..COUNT {
+                    tx.map(RECORD).unwrap().fill(i as u8);
+                }
+                tx.channel().close();
+            })
+        };
+
+        let mut received = Vec::new();
+        while let Some(region) = remote_rx.recv() {
+            assert_eq!(region.len() % RECORD, 0);
+            received.extend_from_slice(&region);
+        }
+
+        producer.join().unwrap();
+        assert_eq!(client.join().unwrap(), (RECORD * COUNT) as u64);
+        assert_eq!(server.join().unwrap(), (RECORD * COUNT) as u64);
+        assert_eq!(received.len(), RECORD * COUNT);
+        for (i, record) in received.chunks(RECORD).enumerate() {
+            assert!(record.iter().all(|&b| b == i as u8));
+        }
+    }
+
+    #[test]
+    fn a_frame_cut_short_is_not_published() {
+        let mut stream = MAGIC.to_vec();
+        stream.push(VERSION);
+        stream.extend_from_slice(&10u64.to_le_bytes());
+        for fill in [1, 2] {
+            stream.extend_from_slice(&10u64.to_le_bytes());
+            stream.extend_from_slice(&[fill; 10]);
+        }
+        // The peer went away 4 bytes into the second frame.
+        stream.truncate(stream.len() - 6);
+
+        let (mut tx, mut rx) = channel(100);
+        assert!(feed(&stream[..], &mut tx).is_err());
+        assert_eq!(&*rx.next().unwrap(), &[1; 10]);
+        assert!(rx.next().is_none());
+        assert!(!rx.is_open());
+    }
+}
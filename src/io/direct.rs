@@ -0,0 +1,277 @@
+//! Streams a channel to disk with `O_DIRECT`, bypassing the page cache.
+
+use std::{
+    alloc::{self, Layout},
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::Path,
+    ptr::NonNull,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::base::{Receiver, ALIGNMENT};
+
+/// Default size of the bounce buffer used for unaligned data.
+pub const DEFAULT_BOUNCE_SIZE: usize = 1 << 20;
+
+/// Writes everything a [`Receiver`] reads to a file opened with `O_DIRECT`.
+///
+/// The channel's buffer is aligned to [`ALIGNMENT`], so whenever the read
+/// position is block aligned whole blocks are written straight from the
+/// ring. Unaligned data, e.g. after records that aren't a multiple of the
+/// block size, is gathered in a bounce buffer first. The final partial
+/// block is padded for the write and the file is truncated to the number
+/// of bytes actually streamed.
+///
+/// Falls back to ordinary buffered writes on filesystems that don't support
+/// `O_DIRECT`.
+pub struct DirectSink {
+    file: File,
+    direct: bool,
+    bounce: Bounce,
+}
+
+/// How much a [`DirectSink`] wrote and how long it took.
+#[derive(Debug, Clone, Copy)]
+pub struct SinkReport {
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl SinkReport {
+    /// Sustained throughput in bytes per second.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl DirectSink {
+    /// Creates (or truncates) the file at `path`, reserving `preallocate`
+    /// bytes on disk up front.
+    pub fn create(path: impl AsRef<Path>, preallocate: u64) -> io::Result<Self> {
+        Self::with_bounce_size(path, preallocate, DEFAULT_BOUNCE_SIZE)
+    }
+
+    /// Like `create` with a bounce buffer of `bounce_size` bytes, rounded up
+    /// to a multiple of the block size.
+    pub fn with_bounce_size(
+        path: impl AsRef<Path>,
+        preallocate: u64,
+        bounce_size: usize,
+    ) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        let (file, direct) = match options.clone().custom_flags(libc::O_DIRECT).open(path) {
+            Ok(file) => (file, true),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                warn!(
+                    "O_DIRECT is not supported for {}. Using buffered writes.",
+                    path.to_string_lossy()
+                );
+                (options.open(path)?, false)
+            }
+            Err(e) => return Err(e),
+        };
+
+        if preallocate > 0 {
+            let err = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, preallocate as _) };
+            if err != 0 {
+                return Err(io::Error::from_raw_os_error(err));
+            }
+        }
+
+        Ok(DirectSink {
+            file,
+            direct,
+            bounce: Bounce::new(round_up(bounce_size.max(1))),
+        })
+    }
+
+    /// True if the file was opened with `O_DIRECT`.
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+    /// Drains `rx` to the file until the channel is closed and everything
+    /// has been read. Fails if the channel is poisoned.
+    pub fn write_from(mut self, rx: &mut Receiver) -> io::Result<SinkReport> {
+        let t0 = Instant::now();
+        let mut bytes = 0u64;
+
+        while let Some(spans) = rx.try_wait_readable().map_err(io::Error::other)? {
+            let (ptr, len) = spans.first;
+            let aligned = (ptr as usize).is_multiple_of(ALIGNMENT);
+
+            // Once the bounce buffer holds whole blocks it can be flushed so
+            // that aligned data in the ring goes out directly again.
+            if aligned && self.bounce.fill.is_multiple_of(ALIGNMENT) && self.bounce.fill > 0 {
+                self.file.write_all(self.bounce.filled())?;
+                self.bounce.fill = 0;
+            }
+
+            let n = if aligned && self.bounce.fill == 0 && len >= ALIGNMENT {
+                let n = len - len % ALIGNMENT;
+                self.file
+                    .write_all(unsafe { std::slice::from_raw_parts(ptr, n) })?;
+                n
+            } else {
+                let n = self
+                    .bounce
+                    .push(unsafe { std::slice::from_raw_parts(ptr, len) });
+                if self.bounce.is_full() {
+                    self.file.write_all(self.bounce.filled())?;
+                    self.bounce.fill = 0;
+                }
+                n
+            };
+            rx.advance(&spans, n);
+            bytes += n as u64;
+        }
+
+        // The last write has to be a whole number of blocks too. Pad it, then
+        // cut the file back to the bytes that were streamed. This also drops
+        // any preallocated space that went unused.
+        if self.bounce.fill > 0 {
+            let fill = self.bounce.fill;
+            let padded = round_up(fill);
+            self.bounce.as_mut_slice()[fill..padded].fill(0);
+            self.bounce.fill = padded;
+            self.file.write_all(self.bounce.filled())?;
+        }
+        self.file.set_len(bytes)?;
+
+        let report = SinkReport {
+            bytes,
+            elapsed: t0.elapsed(),
+        };
+        info!(
+            "DirectSink: wrote {} bytes in {:?} ({:.3} GB/s)",
+            report.bytes,
+            report.elapsed,
+            report.throughput() * 1e-9
+        );
+        Ok(report)
+    }
+}
+
+fn round_up(n: usize) -> usize {
+    n.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+/// Block aligned staging buffer for data that can't be written in place.
+struct Bounce {
+    ptr: NonNull<u8>,
+    capacity: usize,
+    fill: usize,
+}
+
+unsafe impl Send for Bounce {}
+
+impl Bounce {
+    fn new(capacity: usize) -> Self {
+        let layout = Layout::from_size_align(capacity, ALIGNMENT).unwrap();
+        let ptr = unsafe { alloc::alloc(layout) };
+        let ptr = match NonNull::new(ptr) {
+            Some(p) => p,
+            None => alloc::handle_alloc_error(layout),
+        };
+        Bounce {
+            ptr,
+            capacity,
+            fill: 0,
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.capacity) }
+    }
+
+    fn filled(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.fill) }
+    }
+
+    fn is_full(&self) -> bool {
+        self.fill == self.capacity
+    }
+
+    /// Copies as much of `buf` as fits. Returns the number of bytes copied.
+    fn push(&mut self, buf: &[u8]) -> usize {
+        let n = buf.len().min(self.capacity - self.fill);
+        let fill = self.fill;
+        self.as_mut_slice()[fill..fill + n].copy_from_slice(&buf[..n]);
+        self.fill += n;
+        n
+    }
+}
+
+impl Drop for Bounce {
+    fn drop(&mut self) {
+        unsafe {
+            let layout = Layout::from_size_align_unchecked(self.capacity, ALIGNMENT);
+            alloc::dealloc(self.ptr.as_ptr(), layout)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::DirectSink;
+    use crate::base::{channel, ALIGNMENT};
+
+    /// Opens a sink named `name` in the first directory that supports
+    /// `O_DIRECT`. The temp dir is often a tmpfs that doesn't, the working
+    /// directory usually is on disk. Falls back to buffered writes in the
+    /// temp dir if neither does.
+    fn open_sink(name: &str) -> (DirectSink, PathBuf) {
+        let dirs = [std::env::temp_dir(), std::env::current_dir().unwrap()];
+        for dir in &dirs {
+            let path = dir.join(name);
+            let sink = DirectSink::with_bounce_size(&path, 1 << 20, 2 * ALIGNMENT).unwrap();
+            if sink.is_direct() {
+                return (sink, path);
+            }
+            drop(sink);
+            std::fs::remove_file(&path).unwrap();
+        }
+        let path = dirs[0].join(name);
+        let sink = DirectSink::with_bounce_size(&path, 1 << 20, 2 * ALIGNMENT).unwrap();
+        (sink, path)
+    }
+
+    #[test]
+    fn aligned_and_unaligned_records_round_trip() {
+        let (sink, path) = open_sink("gyoll_direct_sink.raw");
+        let (mut tx, mut rx) = channel(8 * ALIGNMENT);
+
+        let writer = std::thread::spawn(move || {
+            let mut expected = Vec::new();
+            // Whole blocks, then odd sized records that force the bounce
+            // buffer and unaligned wraps.
+            for (i, size) in [ALIGNMENT, 2 * ALIGNMENT, 17, 4099, 3 * ALIGNMENT, 1]
+                .iter()
+                .cycle()
+                .take(30)
+                .enumerate()
+            {
+                let mut buf = tx.map(*size).unwrap();
+                buf.fill(i as u8);
+                expected.extend_from_slice(&buf);
+            }
+            tx.channel().close();
+            expected
+        });
+
+        let report = sink.write_from(&mut rx).unwrap();
+        let expected = writer.join().unwrap();
+
+        assert_eq!(report.bytes, expected.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+use std::io::{self, BufRead, Read};
+
+use crate::base::{cursor::Interval, Receiver};
+
+/// Adapts a [`Receiver`] to [`std::io::Read`] and [`std::io::BufRead`].
+///
+/// [`fill_buf`](BufRead::fill_buf) hands out the bytes of the current region
+/// directly from the channel; the region is released once it has been fully
+/// [`consume`](BufRead::consume)d. Reads block until data is available and
+/// return `Ok(0)` only once the channel is closed and drained. They fail
+/// once the channel is poisoned.
+pub struct ReceiverReader {
+    receiver: Receiver,
+    region: Option<Held>,
+}
+
+/// A region acquired by the reader that hasn't been fully consumed.
+struct Held {
+    cur: Interval,
+    ptr: *const u8,
+    len: usize,
+    pos: usize,
+}
+
+unsafe impl Send for ReceiverReader {}
+
+impl ReceiverReader {
+    pub fn new(receiver: Receiver) -> Self {
+        ReceiverReader {
+            receiver,
+            region: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &Receiver {
+        &self.receiver
+    }
+
+    /// Releases any partially consumed region and returns the receiver.
+    ///
+    /// Unconsumed bytes of that region are skipped.
+    pub fn into_inner(mut self) -> Receiver {
+        self.release();
+        let receiver = unsafe { std::ptr::read(&self.receiver) };
+        std::mem::forget(self);
+        receiver
+    }
+
+    fn release(&mut self) {
+        if let Some(held) = self.region.take() {
+            self.receiver.unreserve(&held.cur);
+        }
+    }
+}
+
+impl Read for ReceiverReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for ReceiverReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.region.is_none() {
+            if let Some((cur, ptr)) = self.receiver.try_recv_raw().map_err(io::Error::other)? {
+                self.region = Some(Held {
+                    cur,
+                    ptr,
+                    len: cur.len() as usize,
+                    pos: 0,
+                });
+            }
+        }
+        Ok(match self.region.as_ref() {
+            Some(held) => unsafe {
+                std::slice::from_raw_parts(held.ptr.add(held.pos), held.len - held.pos)
+            },
+            None => &[],
+        })
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Some(held) = self.region.as_mut() {
+            held.pos = (held.pos + amt).min(held.len);
+            if held.pos == held.len {
+                self.release();
+            }
+        }
+    }
+}
+
+impl Drop for ReceiverReader {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, Read, Write},
+        panic::{catch_unwind, AssertUnwindSafe},
+    };
+
+    use super::ReceiverReader;
+    use crate::{
+        base::{channel, Error},
+        io::SenderWriter,
+    };
+
+    #[test]
+    fn read_to_end_after_close() {
+        let (tx, rx) = channel(16);
+        let ch = tx.channel().clone();
+        let writer = std::thread::spawn(move || {
+            let mut w = SenderWriter::with_chunk_size(tx, 4);
+            for line in ["alpha\n", "beta\n", "gamma\n"] {
+                w.write_all(line.as_bytes()).unwrap();
+            }
+            w.flush().unwrap();
+            drop(w);
+            ch.close();
+        });
+
+        let mut out = String::new();
+        ReceiverReader::new(rx).read_to_string(&mut out).unwrap();
+        writer.join().unwrap();
+        assert_eq!(out, "alpha\nbeta\ngamma\n");
+    }
+
+    #[test]
+    fn lines_cross_region_boundaries() {
+        let (mut tx, rx) = channel(32);
+        tx.map(7).unwrap().copy_from_slice(b"one\ntwo");
+        tx.map(4).unwrap().copy_from_slice(b"\nthr");
+        tx.map(3).unwrap().copy_from_slice(b"ee\n");
+        tx.channel().close();
+
+        let lines: Vec<String> = ReceiverReader::new(rx)
+            .lines()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(lines, ["one", "two", "three"]);
+    }
+
+    #[test]
+    fn reads_fail_once_the_channel_is_poisoned() {
+        let (mut tx, rx) = channel(16);
+        tx.channel().set_poison_on_panic(true);
+        tx.map(4).unwrap().copy_from_slice(b"one\n");
+        let mut reader = ReceiverReader::new(rx);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "one\n");
+
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _region = tx.map(4).unwrap();
+            panic!("filling the region failed");
+        }));
+        for _ in 0..2 {
+            let error = reader.read(&mut [0; 4]).unwrap_err().into_inner().unwrap();
+            assert!(matches!(error.downcast_ref(), Some(Error::Poisoned(_))));
+        }
+    }
+}
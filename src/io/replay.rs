@@ -0,0 +1,278 @@
+//! Records everything that passes through a channel to a file, and plays
+//! it back into another channel later, e.g. to reproduce a bug further down
+//! a pipeline.
+//!
+//! The file starts with a header and holds one entry per record:
+//!
+//! ```text
+//! header: b"gyollrec" version:u8 capacity:u64
+//! entry:  elapsed_ns:u64 timestamp:u64 frame:u64 tag:[u8;16] flags:u8 crc:u32
+//!         len:u64 bytes[len]
+//! end:    u64::MAX
+//! ```
+//!
+//! All integers are little endian. `capacity` is the size of the channel
+//! that was recorded and `elapsed_ns` is when the record was committed,
+//! counted from when the first one recorded was. `timestamp`, `frame` and
+//! `tag` are the record's [`Meta`]. Bit 0 of `flags` is set if the record
+//! [is corrupt](crate::base::Record::is_corrupt), and bit 1 if it has a
+//! [checksum](crate::base::Record::checksum), which is `crc`.
+
+use std::{
+    io::{self, Read, Write},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::base::{Meta, Receiver, Sender};
+use crate::io::map_failed;
+
+const MAGIC: &[u8; 8] = b"gyollrec";
+const VERSION: u8 = 2;
+const END_OF_RECORDING: u64 = u64::MAX;
+const HEADER: usize = 17;
+const ENTRY: usize = 53;
+const CORRUPT: u8 = 1;
+const CHECKSUM: u8 = 2;
+
+/// How fast [`replay`] sends records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    /// As fast as the channel takes them.
+    Fast,
+    /// Each record is sent no earlier than it was committed when it was
+    /// recorded, relative to the first.
+    Original,
+}
+
+/// Writes every record `rx` reads to `out` until the channel is closed and
+/// drained. If the channel is poisoned, fails without ending the recording,
+/// so replaying it fails too.
+///
+/// Records are written as they are, including ones that are corrupt or
+/// don't match their checksum.
+///
+/// Returns the number of records written.
+pub fn record<W: Write>(rx: &mut Receiver, mut out: W) -> io::Result<u64> {
+    let mut header = [0u8; HEADER];
+    header[..8].copy_from_slice(MAGIC);
+    header[8] = VERSION;
+    header[9..].copy_from_slice(&(rx.channel().capacity() as u64).to_le_bytes());
+    out.write_all(&header)?;
+
+    let mut t0 = None;
+    let mut count = 0u64;
+    while let Some(record) = rx.try_recv_record_unverified().map_err(io::Error::other)? {
+        let t0 = *t0.get_or_insert(record.committed());
+        let elapsed = record.committed().saturating_duration_since(t0).as_nanos() as u64;
+        let meta = record.meta();
+        let mut flags = 0;
+        if record.is_corrupt() {
+            flags |= CORRUPT;
+        }
+        if record.checksum().is_some() {
+            flags |= CHECKSUM;
+        }
+        let mut entry = [0u8; ENTRY];
+        entry[..8].copy_from_slice(&elapsed.to_le_bytes());
+        entry[8..16].copy_from_slice(&meta.timestamp.to_le_bytes());
+        entry[16..24].copy_from_slice(&meta.frame.to_le_bytes());
+        entry[24..40].copy_from_slice(&meta.tag);
+        entry[40] = flags;
+        entry[41..45].copy_from_slice(&record.checksum().unwrap_or(0).to_le_bytes());
+        entry[45..].copy_from_slice(&(record.len() as u64).to_le_bytes());
+        out.write_all(&entry)?;
+        out.write_all(&record)?;
+        count += 1;
+    }
+    out.write_all(&END_OF_RECORDING.to_le_bytes())?;
+    out.flush()?;
+    debug!("replay: recorded {} records", count);
+    Ok(count)
+}
+
+/// Reads the header of a recording and returns the capacity of the channel
+/// it was made from.
+///
+/// [`replay`] reads the header itself, so this is only for picking the size
+/// of the channel to replay into.
+pub fn read_capacity<R: Read>(mut input: R) -> io::Result<usize> {
+    let mut header = [0u8; HEADER];
+    input.read_exact(&mut header)?;
+    if &header[..8] != MAGIC || header[8] != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a gyoll recording",
+        ));
+    }
+    Ok(u64::from_le_bytes(header[9..].try_into().unwrap()) as usize)
+}
+
+/// Sends the records in a recording made by [`record`] to `tx`, with the
+/// same boundaries, metadata, bytes, checksums and corrupt marks.
+///
+/// Closes the channel when the recording ends, whether or not it was
+/// complete. A record cut short by the end of the file isn't sent. Returns
+/// the number of records sent.
+pub fn replay<R: Read>(mut input: R, tx: &mut Sender, timing: Timing) -> io::Result<u64> {
+    let result = replay_entries(&mut input, tx, timing);
+    tx.channel().close();
+    result
+}
+
+fn replay_entries<R: Read>(input: &mut R, tx: &mut Sender, timing: Timing) -> io::Result<u64> {
+    read_capacity(&mut *input)?;
+    let t0 = Instant::now();
+    let mut count = 0u64;
+    loop {
+        let mut elapsed = [0u8; 8];
+        input.read_exact(&mut elapsed)?;
+        let elapsed = match u64::from_le_bytes(elapsed) {
+            END_OF_RECORDING => break,
+            ns => Duration::from_nanos(ns),
+        };
+        let mut entry = [0u8; ENTRY - 8];
+        input.read_exact(&mut entry)?;
+        let field = |i: usize| u64::from_le_bytes(entry[i..i + 8].try_into().unwrap());
+        let meta = Meta {
+            timestamp: field(0),
+            frame: field(8),
+            tag: entry[16..32].try_into().unwrap(),
+        };
+        let flags = entry[32];
+        let crc = u32::from_le_bytes(entry[33..37].try_into().unwrap());
+        let len = field(37) as usize;
+
+        if timing == Timing::Original {
+            sleep(elapsed.saturating_sub(t0.elapsed()));
+        }
+        let mut region = tx
+            .try_map_with_meta(len, meta)
+            .map_err(|e| map_failed(e, "channel closed"))?;
+        if let Err(e) = input.read_exact(&mut region) {
+            region.discard("replay: the recording ended part way through a record");
+            return Err(e);
+        }
+        region.commit_copy(flags & CORRUPT != 0, (flags & CHECKSUM != 0).then_some(crc));
+        count += 1;
+    }
+    debug!("replay: replayed {} records", count);
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::{catch_unwind, AssertUnwindSafe},
+        thread::{sleep, spawn},
+        time::{Duration, Instant},
+    };
+
+    use super::{read_capacity, record, replay, Timing};
+    use crate::base::{channel, ChannelFactory, Meta};
+
+    /// Reads every record until the channel is closed.
+    fn drain(mut rx: crate::base::Receiver) -> Vec<(Meta, Vec<u8>)> {
+        let mut records = Vec::new();
+        while let Some(r) = rx.recv_record() {
+            records.push((*r.meta(), r.to_vec()));
+        }
+        records
+    }
+
+    #[test]
+    fn replay_reproduces_the_stream() {
+        let (mut tx, mut rx) = channel(64);
+        let producer = spawn(move || {
+            for i in 0..20u8 {
+                let meta = Meta {
+                    timestamp: 1000 + i as u64,
+                    frame: i as u64,
+                    tag: [i; 16],
+                };
+                tx.map_with_meta(1 + i as usize, meta).unwrap().fill(i);
+            }
+            tx.channel().close();
+        });
+        let mut file = Vec::new();
+        assert_eq!(record(&mut rx, &mut file).unwrap(), 20);
+        producer.join().unwrap();
+        assert_eq!(read_capacity(&file[..]).unwrap(), 64);
+
+        // Replays into a smaller channel than the one recorded.
+        let (mut tx, rx) = channel(32);
+        let reader = spawn(move || drain(rx));
+        assert_eq!(replay(&file[..], &mut tx, Timing::Fast).unwrap(), 20);
+        let records = reader.join().unwrap();
+        assert_eq!(records.len(), 20);
+        for (i, (meta, bytes)) in records.iter().enumerate() {
+            assert_eq!(
+                (meta.timestamp, meta.frame, meta.tag[0]),
+                (1000 + i as u64, i as u64, i as u8)
+            );
+            assert_eq!(bytes, &vec![i as u8; 1 + i]);
+        }
+
+        // A truncated recording still closes the channel. The record it
+        // ends in isn't sent.
+        let (mut tx, rx) = channel(32);
+        assert!(replay(&file[..100], &mut tx, Timing::Fast).is_err());
+        let records = drain(rx);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].1, vec![0u8; 1]);
+    }
+
+    #[test]
+    fn replay_keeps_the_original_timing() {
+        let (mut tx, mut rx) = channel(64);
+        let producer = spawn(move || {
+            tx.map(4).unwrap().fill(1);
+            sleep(Duration::from_millis(50));
+            tx.map(4).unwrap().fill(2);
+            tx.channel().close();
+        });
+        // Recording late doesn't change when the records were sent.
+        producer.join().unwrap();
+        let mut file = Vec::new();
+        record(&mut rx, &mut file).unwrap();
+
+        let (mut tx, rx) = channel(64);
+        let t0 = Instant::now();
+        replay(&file[..], &mut tx, Timing::Original).unwrap();
+        assert!(t0.elapsed() >= Duration::from_millis(40));
+        assert_eq!(drain(rx).len(), 2);
+    }
+
+    #[test]
+    fn replay_keeps_corrupt_marks_and_checksums() {
+        let (mut tx, mut rx) = channel(64);
+        let mut other = tx.channel().sender();
+        tx.channel().set_checksums(true);
+        tx.map(2).unwrap().fill(1);
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _region = tx.map(2).unwrap();
+            other.map(2).unwrap().fill(3);
+            panic!("filling the region failed");
+        }));
+        tx.channel().close();
+        let mut file = Vec::new();
+        assert_eq!(record(&mut rx, &mut file).unwrap(), 3);
+
+        let (mut tx, mut rx) = channel(64);
+        replay(&file[..], &mut tx, Timing::Fast).unwrap();
+        let mut integrity = Vec::new();
+        while let Some(r) = rx.recv_record() {
+            integrity.push((r.is_corrupt(), r.checksum()));
+        }
+        assert_eq!(
+            integrity,
+            [
+                (false, Some(crc32c::crc32c(&[1; 2]))),
+                (true, None),
+                (false, Some(crc32c::crc32c(&[3; 2]))),
+            ]
+        );
+    }
+}
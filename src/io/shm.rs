@@ -0,0 +1,728 @@
+//! Shares a memfd backed channel with other processes over a Unix socket.
+//!
+//! A [`Server`] listens on a socket path. A client connects with [`sender`]
+//! or [`receiver`] and gets back a plain [`Sender`] or [`Receiver`] on a
+//! channel of its own, whose buffer is the server's memfd mapped into the
+//! client. Region contents never cross the socket, and mapping, committing
+//! and reading don't wait on it either. Only the bookkeeping does, in the
+//! background:
+//!
+//! ```text
+//! hello:   role:u8
+//! header:  b"gyoll" version:u8 role:u8 status:u8 capacity:u64 alignment:u64 start:u64
+//! message: kind:u8 a:u64 b:u64
+//! ```
+//!
+//! All integers are little endian. After the header, the side that writes
+//! the stream forwards each region once its channel committed it, as its
+//! offset `a` and length `b` in the buffer. The other side repeats the region
+//! in its own channel, and reports back how many of the forwarded regions its
+//! receivers have released, as `a`, so the first side can reuse their space.
+//! `start` is the offset the client's channel starts at.
+//!
+//! Either side hanging up releases what it held on the other. A client
+//! sender's regions only reach the server once they're committed, so ones it
+//! was still writing when it went away are never published.
+//!
+//! While a client sender is attached it has to be the channel's only sender,
+//! since the client's channel picks where each region goes.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    mem::size_of,
+    net::Shutdown,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{spawn, JoinHandle},
+};
+
+use log::{debug, warn};
+use parking_lot::Mutex;
+
+use crate::base::{map_shared, Channel, ChannelFactory, Receiver, Sender, Signal, ALIGNMENT};
+
+const MAGIC: &[u8; 5] = b"gyoll";
+const VERSION: u8 = 2;
+const HEADER_LEN: usize = 32;
+const MESSAGE_LEN: usize = 17;
+
+const ROLE_SENDER: u8 = 0;
+const ROLE_RECEIVER: u8 = 1;
+
+const STATUS_OK: u8 = 0;
+const STATUS_BUSY: u8 = 1;
+
+const MSG_REGION: u8 = 0;
+const MSG_END: u8 = 1;
+const MSG_RELEASED: u8 = 2;
+
+/// Hands out a memfd backed channel to clients connecting on a Unix socket.
+pub struct Server {
+    listener: UnixListener,
+    channel: Arc<Channel>,
+    has_sender: Arc<AtomicBool>,
+}
+
+impl Server {
+    /// Listens on `path` for clients of `channel`, which must have been
+    /// created with [`Channel::memfd`].
+    pub fn bind(path: impl AsRef<Path>, channel: Arc<Channel>) -> io::Result<Self> {
+        if channel.memfd_raw_fd().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only memfd backed channels can be shared",
+            ));
+        }
+        Ok(Server {
+            listener: UnixListener::bind(path)?,
+            channel,
+            has_sender: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn channel(&self) -> &Arc<Channel> {
+        &self.channel
+    }
+
+    /// Accepts one client and serves it on a new thread.
+    ///
+    /// The handshake happens on that thread too, so a client that never
+    /// says what it wants only holds up itself. The thread finishes when the
+    /// client disconnects.
+    pub fn accept(&self) -> io::Result<JoinHandle<io::Result<()>>> {
+        let (stream, _) = self.listener.accept()?;
+        let channel = self.channel.clone();
+        let has_sender = self.has_sender.clone();
+        Ok(spawn(move || serve(channel, stream, &has_sender)))
+    }
+
+    /// Serves clients until accepting one fails.
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            self.accept()?;
+        }
+    }
+}
+
+fn serve(channel: Arc<Channel>, mut stream: UnixStream, has_sender: &AtomicBool) -> io::Result<()> {
+    let mut role = [0u8];
+    stream.read_exact(&mut role)?;
+    match role[0] {
+        ROLE_SENDER => {
+            if has_sender.swap(true, Ordering::AcqRel) {
+                send_header(&stream, &channel, ROLE_SENDER, STATUS_BUSY, 0)?;
+                return Err(busy(ROLE_SENDER));
+            }
+            let _attached = Attached(has_sender);
+            let tx = channel.sender();
+            wait_for_readers(&channel);
+            send_header(&stream, &channel, ROLE_SENDER, STATUS_OK, tx.offset())?;
+            debug!("shm: attached a sender");
+            let result = mirror(tx, stream, Side::Server);
+            debug!("shm: sender detached");
+            result
+        }
+        ROLE_RECEIVER => {
+            let rx = match channel.try_receiver() {
+                Some(rx) => rx,
+                None => {
+                    send_header(&stream, &channel, ROLE_RECEIVER, STATUS_BUSY, 0)?;
+                    return Err(busy(ROLE_RECEIVER));
+                }
+            };
+            send_header(&stream, &channel, ROLE_RECEIVER, STATUS_OK, rx.offset())?;
+            debug!("shm: attached a receiver");
+            let result = forward(rx, stream, Side::Server);
+            debug!("shm: receiver detached");
+            result
+        }
+        role => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown role {}", role),
+        )),
+    }
+}
+
+/// Lets another client sender attach once the current one is gone.
+struct Attached<'a>(&'a AtomicBool);
+
+impl Drop for Attached<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Blocks until the readers have released everything written so far, or the
+/// channel is closed.
+///
+/// A client sender's channel starts out empty, so it would reuse the space of
+/// bytes the server's readers still need.
+fn wait_for_readers(channel: &Channel) {
+    let signal = Arc::new(Signal::default());
+    channel.watch(signal.clone());
+    loop {
+        // Read the generation first so a release while checking isn't
+        // missed.
+        let generation = signal.generation();
+        let snapshot = channel.snapshot();
+        if snapshot.closed || snapshot.reads.start == snapshot.writes.end {
+            break;
+        }
+        signal.wait(generation);
+    }
+    channel.unwatch(&signal);
+}
+
+fn send_header(
+    stream: &UnixStream,
+    channel: &Channel,
+    role: u8,
+    status: u8,
+    start: usize,
+) -> io::Result<()> {
+    let mut header = [0u8; HEADER_LEN];
+    header[..5].copy_from_slice(MAGIC);
+    header[5] = VERSION;
+    header[6] = role;
+    header[7] = status;
+    header[8..16].copy_from_slice(&(channel.capacity() as u64).to_le_bytes());
+    header[16..24].copy_from_slice(&(ALIGNMENT as u64).to_le_bytes());
+    header[24..].copy_from_slice(&(start as u64).to_le_bytes());
+    send_with_fd(stream, &header, channel.memfd_raw_fd().unwrap())
+}
+
+fn busy(role: u8) -> io::Error {
+    io::Error::other(match role {
+        ROLE_SENDER => "the channel already has a sender in another process",
+        _ => "the channel has an exclusive receiver",
+    })
+}
+
+/// Connects to the server listening on `path` as a sender.
+///
+/// The sender's channel lives in this process, over the server's buffer, so
+/// regions are written straight into shared memory without waiting on the
+/// server. Threads in the background forward each region to the server once
+/// it's committed, and give its space back once the server's receivers have
+/// released it. Closing the channel closes the server's too, once what was
+/// sent before has been forwarded.
+///
+/// Fails if another client sender is attached. The server's channel can't
+/// have other senders in the meantime, and the connection waits for its
+/// receivers to catch up with what was sent before.
+pub fn sender(path: impl AsRef<Path>) -> io::Result<Sender> {
+    let (channel, stream) = connect(path.as_ref(), ROLE_SENDER)?;
+    let rx = channel.receiver();
+    let tx = channel.sender();
+    spawn(move || {
+        if let Err(e) = forward(rx, stream, Side::Client) {
+            warn!("shm: lost the server: {}", e);
+        }
+    });
+    Ok(tx)
+}
+
+/// Connects to the server listening on `path` as a receiver.
+///
+/// Like [`sender`], the receiver's channel lives in this process, over the
+/// server's buffer. It's closed when the server's is closed and drained, or
+/// when the connection is lost. Dropping the last receiver on it hangs up.
+///
+/// The receiver sees everything sent after this returns. Record boundaries
+/// and metadata don't cross: each region the server's channel hands out
+/// arrives as one record.
+///
+/// Fails if the server's channel has an exclusive receiver.
+pub fn receiver(path: impl AsRef<Path>) -> io::Result<Receiver> {
+    let (channel, stream) = connect(path.as_ref(), ROLE_RECEIVER)?;
+    let rx = channel.receiver();
+    let tx = channel.sender();
+    spawn(move || {
+        if let Err(e) = mirror(tx, stream, Side::Client) {
+            warn!("shm: lost the server: {}", e);
+        }
+    });
+    Ok(rx)
+}
+
+fn connect(path: &Path, role: u8) -> io::Result<(Arc<Channel>, UnixStream)> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(&[role])?;
+
+    let mut header = [0u8; HEADER_LEN];
+    let fd = recv_with_fd(&stream, &mut header)?;
+    if &header[..5] != MAGIC || header[5] != VERSION || header[6] != role {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a gyoll shared memory server",
+        ));
+    }
+    if header[7] == STATUS_BUSY {
+        return Err(busy(role));
+    }
+    let field = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap()) as usize;
+    let (capacity, alignment, start) = (field(8), field(16), field(24));
+    if alignment != ALIGNMENT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("server uses {} byte alignment", alignment),
+        ));
+    }
+    if start > capacity {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("stream starts at {} in a {} byte buffer", start, capacity),
+        ));
+    }
+
+    // The mapping keeps the memory alive, so the fd isn't needed after.
+    let ptr = map_shared(fd.as_raw_fd(), capacity, libc::PROT_READ | libc::PROT_WRITE)?;
+    let channel = Channel::mapped(ptr, capacity, start);
+    Ok((Arc::new(channel), stream))
+}
+
+/// Which end of the connection a session runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Server,
+    /// The channel only exists for this connection, so it's closed when the
+    /// server hangs up.
+    Client,
+}
+
+/// Forwards the regions `rx` reads to the peer, and holds on to their bytes
+/// until the peer reports its receivers released them.
+///
+/// The bytes are held by a receiver depending on `rx`, which lets go of as
+/// many bytes as the released regions held. Returns once the channel is
+/// closed and drained and the peer has hung up.
+fn forward(mut rx: Receiver, stream: UnixStream, side: Side) -> io::Result<()> {
+    let channel = rx.channel().clone();
+    // The length of each region the peer hasn't released, oldest first.
+    let lens = Arc::new(Mutex::new(VecDeque::new()));
+    let releases = {
+        let (gate, channel, lens) = (rx.dependent(), channel.clone(), lens.clone());
+        let stream = stream.try_clone()?;
+        spawn(move || release_forwarded(gate, &channel, &lens, &stream, side))
+    };
+
+    let base = channel.as_ptr() as usize;
+    let sent = (|| {
+        while let Some(region) = rx.try_recv().map_err(io::Error::other)? {
+            let (offset, len) = (region.as_ptr() as usize - base, region.len());
+            // The gate holds on to the bytes from here.
+            drop(region);
+            lens.lock().push_back(len as u64);
+            write_message(&stream, (MSG_REGION, offset as u64, len as u64))?;
+        }
+        write_message(&stream, (MSG_END, 0, 0))
+    })();
+    if sent.is_err() {
+        // Make sure the peer hangs up, so `release_forwarded` finishes.
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    let released = releases
+        .join()
+        .expect("releasing forwarded regions panicked");
+    sent.and(released)
+}
+
+/// Lets `gate` go past forwarded regions as the peer reports them released,
+/// and drops it once the peer hangs up, letting go of the rest.
+fn release_forwarded(
+    mut gate: Receiver,
+    channel: &Channel,
+    lens: &Mutex<VecDeque<u64>>,
+    stream: &UnixStream,
+    side: Side,
+) -> io::Result<()> {
+    let result = (|| {
+        while let Some((kind, count, _)) = read_message(stream)? {
+            if kind != MSG_RELEASED {
+                return Err(unknown_message(kind));
+            }
+            let nbytes = {
+                let mut lens = lens.lock();
+                if count > lens.len() as u64 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} regions released, {} forwarded", count, lens.len()),
+                    ));
+                }
+                lens.drain(..count as usize).sum()
+            };
+            skip(&mut gate, nbytes)?;
+        }
+        Ok(())
+    })();
+
+    drop(gate);
+    if side == Side::Client {
+        channel.close();
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+/// Releases the next `nbytes` readable by `rx` without looking at them.
+fn skip(rx: &mut Receiver, mut nbytes: u64) -> io::Result<()> {
+    while nbytes > 0 {
+        let mut budget = Budget(nbytes);
+        match rx.drain_to(&mut budget)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => nbytes -= n as u64,
+        }
+    }
+    Ok(())
+}
+
+/// A writer that takes up to a number of bytes and throws them away.
+struct Budget(u64);
+
+impl Write for Budget {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.0 as usize);
+        self.0 -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Mirroring,
+    /// The peer sent everything it will.
+    Ended,
+    HungUp,
+}
+
+/// Regions mirrored from the peer that this channel's receivers haven't
+/// released yet.
+struct Mirrored {
+    /// The stream position of the end of each region, oldest first.
+    ends: VecDeque<u64>,
+    phase: Phase,
+}
+
+/// Commits the regions the peer forwards in `tx`'s channel, and reports
+/// back as its receivers release them.
+///
+/// Returns once the peer sent everything and it's all been released, or
+/// the peer hung up.
+fn mirror(tx: Sender, stream: UnixStream, side: Side) -> io::Result<()> {
+    let channel = tx.channel().clone();
+    let mirrored = Arc::new(Mutex::new(Mirrored {
+        ends: VecDeque::new(),
+        phase: Phase::Mirroring,
+    }));
+    let signal = Arc::new(Signal::default());
+    channel.watch(signal.clone());
+    let reports = {
+        let (channel, mirrored, signal) = (channel.clone(), mirrored.clone(), signal.clone());
+        let stream = stream.try_clone()?;
+        spawn(move || report_releases(&channel, &mirrored, &signal, &stream, side))
+    };
+
+    // `tx` is the only sender, so the regions end one after the other.
+    let mut head = channel.snapshot().writes.end;
+    let received = (|| {
+        while let Some((kind, offset, len)) = read_message(&stream)? {
+            match kind {
+                MSG_REGION => {
+                    let region = tx
+                        .map_raw_at(offset as usize, len as usize)
+                        .map_err(io::Error::other)?;
+                    let (interval, _) = region.ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{} byte region at {} is out of place", len, offset),
+                        )
+                    })?;
+                    tx.unreserve(&interval);
+                    head += len;
+                    mirrored.lock().ends.push_back(head);
+                    signal.notify();
+                }
+                MSG_END => {
+                    channel.close();
+                    return Ok(Phase::Ended);
+                }
+                kind => return Err(unknown_message(kind)),
+            }
+        }
+        Ok(Phase::HungUp)
+    })();
+
+    let phase = *received.as_ref().unwrap_or(&Phase::HungUp);
+    mirrored.lock().phase = phase;
+    signal.notify();
+    if phase == Phase::HungUp {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    if side == Side::Client {
+        channel.close();
+    }
+    let reported = reports.join().expect("reporting releases panicked");
+    channel.unwatch(&signal);
+    received.and(reported)
+}
+
+/// Tells the peer how many mirrored regions have been released whenever the
+/// read tail moves past some.
+fn report_releases(
+    channel: &Channel,
+    mirrored: &Mutex<Mirrored>,
+    signal: &Signal,
+    stream: &UnixStream,
+    side: Side,
+) -> io::Result<()> {
+    loop {
+        // Read the generation first so a change while checking isn't missed.
+        let generation = signal.generation();
+        if side == Side::Client && !channel.has_receivers() {
+            // Nobody will read the rest, so stop the server sending it.
+            channel.close();
+            let _ = stream.shutdown(Shutdown::Both);
+            return Ok(());
+        }
+        let released = channel.released().map_or(0, |p| p.get());
+        let (count, phase, done) = {
+            let mut mirrored = mirrored.lock();
+            let count = mirrored
+                .ends
+                .iter()
+                .take_while(|&&end| end <= released)
+                .count();
+            mirrored.ends.drain(..count);
+            (count, mirrored.phase, mirrored.ends.is_empty())
+        };
+        if phase == Phase::HungUp {
+            return Ok(());
+        }
+        if count > 0 {
+            write_message(stream, (MSG_RELEASED, count as u64, 0))?;
+        }
+        if phase == Phase::Ended && done {
+            return Ok(());
+        }
+        signal.wait(generation);
+    }
+}
+
+fn unknown_message(kind: u8) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unknown message {}", kind),
+    )
+}
+
+/// Reads the next message. Returns None when the peer hung up.
+fn read_message(mut stream: &UnixStream) -> io::Result<Option<(u8, u64, u64)>> {
+    let mut buf = [0u8; MESSAGE_LEN];
+    match stream.read_exact(&mut buf) {
+        Ok(()) => Ok(Some((
+            buf[0],
+            u64::from_le_bytes(buf[1..9].try_into().unwrap()),
+            u64::from_le_bytes(buf[9..].try_into().unwrap()),
+        ))),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_message(mut stream: &UnixStream, (kind, a, b): (u8, u64, u64)) -> io::Result<()> {
+    let mut buf = [0u8; MESSAGE_LEN];
+    buf[0] = kind;
+    buf[1..9].copy_from_slice(&a.to_le_bytes());
+    buf[9..].copy_from_slice(&b.to_le_bytes());
+    stream.write_all(&buf)
+}
+
+fn send_with_fd(stream: &UnixStream, data: &[u8], fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let mut control = vec![0u8; libc::CMSG_SPACE(size_of::<RawFd>() as _) as usize];
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut _,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut _;
+        msg.msg_controllen = control.len() as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<RawFd>() as _) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+
+        let n = libc::sendmsg(stream.as_raw_fd(), &msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n as usize != data.len() {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+    }
+    Ok(())
+}
+
+fn recv_with_fd(stream: &UnixStream, data: &mut [u8]) -> io::Result<OwnedFd> {
+    unsafe {
+        let mut control = vec![0u8; libc::CMSG_SPACE(size_of::<RawFd>() as _) as usize];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut _,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut _;
+        msg.msg_controllen = control.len() as _;
+
+        let n = libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if n as usize != data.len()
+            || cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a header and a file descriptor",
+            ));
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd);
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        os::unix::net::UnixStream,
+        path::{Path, PathBuf},
+        sync::Arc,
+        thread::spawn,
+    };
+
+    use super::{receiver, recv_with_fd, sender, Server, HEADER_LEN, ROLE_SENDER};
+    use crate::base::{Channel, ChannelFactory};
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("gyoll_{}_{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// Sends `count` records of `len` bytes from a client sender, each filled
+    /// with its index, and closes the channel.
+    fn send_records(path: &Path, count: usize, len: usize) {
+        let mut tx = sender(path).unwrap();
+        for i in 0..count {
+            tx.map(len).unwrap().fill(i as u8);
+        }
+        tx.channel().close();
+    }
+
+    #[test]
+    fn client_sender_to_client_receiver() {
+        const RECORD: usize = 100;
+        const COUNT: usize = 200;
+
+        let path = socket_path("shm");
+        // Small enough that the records wrap many times.
+        let channel = Arc::new(Channel::memfd(8 * RECORD).unwrap());
+        let server = Server::bind(&path, channel.clone()).unwrap();
+        let sessions = spawn(move || [server.accept().unwrap(), server.accept().unwrap()]);
+
+        // Attach a local receiver too, to check both see the same data.
+        let mut local = channel.receiver();
+        // Attached once this returns, so it sees every record.
+        let mut rx = receiver(&path).unwrap();
+        let consumer = spawn(move || {
+            let mut received = Vec::new();
+            while let Some(region) = rx.recv() {
+                received.extend_from_slice(&region);
+            }
+            received
+        });
+        let producer = {
+            let path = path.clone();
+            spawn(move || send_records(&path, COUNT, RECORD))
+        };
+
+        let mut seen = Vec::new();
+        while let Some(region) = local.recv() {
+            seen.extend_from_slice(&region);
+        }
+        drop(local);
+
+        producer.join().unwrap();
+        let received = consumer.join().unwrap();
+        for session in sessions.join().unwrap() {
+            session.join().unwrap().unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(received, seen);
+        assert_eq!(received.len(), RECORD * COUNT);
+        for (i, record) in received.chunks(RECORD).enumerate() {
+            assert!(record.iter().all(|&b| b == i as u8));
+        }
+        let report = channel.validate();
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    #[test]
+    fn a_sender_that_goes_away_leaves_the_channel_to_the_next() {
+        let path = socket_path("shm_hangup");
+        let channel = Arc::new(Channel::memfd(1 << 12).unwrap());
+        let server = Server::bind(&path, channel.clone()).unwrap();
+        let mut local = channel.receiver();
+
+        // A client that never says what it wants doesn't hold up the others.
+        let _silent = UnixStream::connect(&path).unwrap();
+        let silent = server.accept().unwrap();
+
+        // One that goes away right after the handshake.
+        let mut gone = UnixStream::connect(&path).unwrap();
+        gone.write_all(&[ROLE_SENDER]).unwrap();
+        let gone_session = server.accept().unwrap();
+        recv_with_fd(&gone, &mut [0u8; HEADER_LEN]).unwrap();
+        drop(gone);
+        gone_session.join().unwrap().unwrap();
+
+        let producer = {
+            let path = path.clone();
+            spawn(move || send_records(&path, 10, 100))
+        };
+        let session = server.accept().unwrap();
+        let mut received = 0;
+        while let Some(region) = local.recv() {
+            received += region.len();
+        }
+        drop(local);
+        producer.join().unwrap();
+        session.join().unwrap().unwrap();
+        assert_eq!(received, 1000);
+        assert!(!silent.is_finished());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
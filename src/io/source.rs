@@ -0,0 +1,289 @@
+//! Fills a channel from a file, stdin or any other reader.
+
+use std::{
+    fs::File,
+    io::{self, IoSliceMut, Read},
+    path::Path,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::base::Sender;
+use crate::io::map_failed;
+
+/// Default number of bytes a [`Source`] reads per region.
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 16;
+
+/// Reads from `R` straight into regions mapped from a [`Sender`].
+///
+/// This is the mirror of a disk sink: use it to replay recorded data into a
+/// channel. Each read goes into a freshly mapped region of up to
+/// `chunk_size` bytes and only the bytes actually read are committed. At end
+/// of file the channel is closed so receivers see the end of the stream.
+pub struct Source<R> {
+    reader: R,
+    chunk_size: usize,
+    vectored: bool,
+    rate: Option<f64>,
+}
+
+impl Source<File> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Source::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Source<R> {
+    pub fn new(reader: R) -> Self {
+        Source {
+            reader,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            vectored: false,
+            rate: None,
+        }
+    }
+
+    /// Maximum number of bytes read per region. Clamped to the capacity of
+    /// the channel.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// When a chunk would wrap around the end of the channel's buffer,
+    /// map both halves and fill them with a single `readv` instead of
+    /// leaving the end of the buffer unused.
+    pub fn vectored(mut self, vectored: bool) -> Self {
+        self.vectored = vectored;
+        self
+    }
+
+    /// Limits the average rate at which bytes are sent to `bytes_per_sec`.
+    ///
+    /// `run` fails with [`io::ErrorKind::InvalidInput`] unless the rate is
+    /// positive and finite.
+    pub fn rate(mut self, bytes_per_sec: f64) -> Self {
+        self.rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Copies the reader into the channel until end of file, then closes
+    /// the channel.
+    ///
+    /// Returns the number of bytes sent. When reading fails, the bytes read
+    /// into the last region are committed if it can be shortened to them,
+    /// and otherwise the channel is poisoned. The same goes for a region left
+    /// partly filled at end of file.
+    pub fn run(mut self, tx: &mut Sender) -> io::Result<u64> {
+        if let Some(rate) = self.rate {
+            if !(rate.is_finite() && rate > 0.0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("can't send at {} bytes/s", rate),
+                ));
+            }
+        }
+        let chunk_size = self.chunk_size.min(tx.channel().capacity());
+        let t0 = Instant::now();
+        let mut total = 0u64;
+        loop {
+            let room = tx.head_room();
+            let n = if self.vectored && 0 < room && room < chunk_size {
+                self.fill_batch(tx, &[room, chunk_size - room])?
+            } else {
+                self.fill_region(tx, chunk_size)?
+            };
+            if n == 0 {
+                break;
+            }
+            total += n as u64;
+
+            if let Some(rate) = self.rate {
+                let due = Duration::from_secs_f64(total as f64 / rate);
+                if let Some(ahead) = due.checked_sub(t0.elapsed()) {
+                    sleep(ahead);
+                }
+            }
+        }
+        debug!("Source: sent {} bytes in {:?}", total, t0.elapsed());
+        tx.channel().close();
+        Ok(total)
+    }
+
+    fn fill_region(&mut self, tx: &mut Sender, nbytes: usize) -> io::Result<usize> {
+        let mut region = tx
+            .try_map(nbytes)
+            .map_err(|e| map_failed(e, "channel closed"))?;
+        let mut filled = 0;
+        // The unused part can only be given back when no one reserved space
+        // behind us. Otherwise keep reading until the region is full.
+        loop {
+            let read = read_some(&mut self.reader, &mut region[filled..]);
+            filled += *read.as_ref().unwrap_or(&0);
+            if region.truncate(filled) {
+                return read.map(|_| filled);
+            }
+            if !matches!(read, Ok(n) if n > 0) {
+                region.discard("Source: the reader stopped part way through");
+                return read.and(Err(cut_short()));
+            }
+        }
+    }
+
+    fn fill_batch(&mut self, tx: &mut Sender, sizes: &[usize]) -> io::Result<usize> {
+        let mut batch = tx
+            .try_map_many(sizes)
+            .map_err(|e| map_failed(e, "channel closed"))?;
+        let mut filled = 0;
+        loop {
+            let read = {
+                let mut slices: Vec<&mut [u8]> = batch.iter_mut().collect();
+                let mut bufs = remaining(&mut slices, filled);
+                read_vectored_some(&mut self.reader, &mut bufs)
+            };
+            filled += *read.as_ref().unwrap_or(&0);
+            if batch.truncate(filled) {
+                return read.map(|_| filled);
+            }
+            if !matches!(read, Ok(n) if n > 0) {
+                batch.discard("Source: the reader stopped part way through");
+                return read.and(Err(cut_short()));
+            }
+        }
+    }
+}
+
+fn cut_short() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "end of file part way through a region that can't be shortened",
+    )
+}
+
+/// The parts of `bufs` after the first `skip` bytes.
+fn remaining<'a>(bufs: &'a mut [&mut [u8]], mut skip: usize) -> Vec<IoSliceMut<'a>> {
+    let mut out = Vec::new();
+    for buf in bufs.iter_mut() {
+        let n = skip.min(buf.len());
+        skip -= n;
+        if n < buf.len() {
+            out.push(IoSliceMut::new(&mut buf[n..]));
+        }
+    }
+    out
+}
+
+/// Like `Read::read` but retries on `Interrupted`.
+fn read_some<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match reader.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            r => return r,
+        }
+    }
+}
+
+fn read_vectored_some<R: Read>(reader: &mut R, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+    loop {
+        match reader.read_vectored(bufs) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            r => return r,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read};
+
+    use super::Source;
+    use crate::{
+        base::{channel, ChannelFactory, Error, Sender},
+        io::ReceiverReader,
+    };
+
+    /// Returns `ok` bytes, then fails. Another sender maps a region during
+    /// the first read if there is one.
+    struct Failing {
+        ok: usize,
+        other: Option<Sender>,
+    }
+
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if let Some(mut other) = self.other.take() {
+                other.map(4).unwrap().fill(9);
+            }
+            let n = self.ok.min(buf.len());
+            if n == 0 {
+                return Err(io::Error::other("disk on fire"));
+            }
+            buf[..n].fill(1);
+            self.ok -= n;
+            Ok(n)
+        }
+    }
+
+    fn round_trip(vectored: bool) {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let (mut tx, rx) = channel(1000);
+
+        let reader = std::thread::spawn(move || {
+            let mut out = Vec::new();
+            ReceiverReader::new(rx).read_to_end(&mut out).unwrap();
+            out
+        });
+        let sent = Source::new(Cursor::new(data.clone()))
+            .chunk_size(300)
+            .vectored(vectored)
+            .run(&mut tx)
+            .unwrap();
+
+        assert_eq!(sent, data.len() as u64);
+        assert_eq!(reader.join().unwrap(), data);
+    }
+
+    #[test]
+    fn source_round_trip() {
+        round_trip(false);
+    }
+
+    #[test]
+    fn source_round_trip_vectored() {
+        round_trip(true);
+    }
+
+    #[test]
+    fn a_failed_read_publishes_only_what_was_read() {
+        let (mut tx, mut rx) = channel(1000);
+        let failing = Failing {
+            ok: 100,
+            other: None,
+        };
+        let err = Source::new(failing).chunk_size(300).run(&mut tx);
+        assert_eq!(err.unwrap_err().to_string(), "disk on fire");
+        assert_eq!(&*rx.next().unwrap(), &[1; 100]);
+        assert!(rx.next().is_none());
+
+        // Behind another sender's region the partial one can't be shortened.
+        let (mut tx, mut rx) = channel(1000);
+        let other = Some(tx.channel().sender());
+        let failing = Failing { ok: 100, other };
+        assert!(Source::new(failing).chunk_size(300).run(&mut tx).is_err());
+        assert!(matches!(rx.try_next(), Err(Error::Poisoned(_))));
+    }
+
+    #[test]
+    fn rates_that_arent_positive_and_finite_are_rejected() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let (mut tx, _rx) = channel(1000);
+            let err = Source::new(Cursor::new(vec![0; 10]))
+                .rate(rate)
+                .run(&mut tx)
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::{self, Write};
+
+use log::warn;
+
+use crate::base::{cursor::Interval, Sender};
+use crate::io::map_failed;
+
+/// Default size of the regions a [`SenderWriter`] maps.
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 16;
+
+/// Adapts a [`Sender`] to [`std::io::Write`].
+///
+/// Bytes are copied into regions of `chunk_size` bytes that are mapped as
+/// needed. A chunk is committed once it is full, or, partially, on
+/// [`flush`](Write::flush). Writes larger than the channel are split across
+/// several chunks.
+pub struct SenderWriter {
+    sender: Sender,
+    chunk_size: usize,
+    chunk: Option<Chunk>,
+}
+
+/// A region mapped by the writer that is still being filled.
+struct Chunk {
+    cur: Interval,
+    ptr: *mut u8,
+    len: usize,
+    filled: usize,
+}
+
+unsafe impl Send for SenderWriter {}
+
+impl SenderWriter {
+    pub fn new(sender: Sender) -> Self {
+        Self::with_chunk_size(sender, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Chunks are clamped to the capacity of the channel.
+    pub fn with_chunk_size(sender: Sender, chunk_size: usize) -> Self {
+        let chunk_size = chunk_size.clamp(1, sender.channel().capacity().max(1));
+        SenderWriter {
+            sender,
+            chunk_size,
+            chunk: None,
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn get_ref(&self) -> &Sender {
+        &self.sender
+    }
+
+    /// Flushes and returns the underlying sender.
+    pub fn into_inner(mut self) -> io::Result<Sender> {
+        self.flush()?;
+        let sender = unsafe { std::ptr::read(&self.sender) };
+        std::mem::forget(self);
+        Ok(sender)
+    }
+
+    fn commit(&mut self) {
+        if let Some(chunk) = self.chunk.take() {
+            self.sender.unreserve(&chunk.cur);
+        }
+    }
+}
+
+impl Write for SenderWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.chunk.is_none() {
+            let (cur, ptr) = self
+                .sender
+                .map_raw(self.chunk_size)
+                .map_err(|e| map_failed(e, "channel closed"))?;
+            self.chunk = Some(Chunk {
+                cur,
+                ptr,
+                len: self.chunk_size,
+                filled: 0,
+            });
+        }
+
+        let chunk = self.chunk.as_mut().unwrap();
+        let n = buf.len().min(chunk.len - chunk.filled);
+        unsafe {
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), chunk.ptr.add(chunk.filled), n);
+        }
+        chunk.filled += n;
+        if chunk.filled == chunk.len {
+            self.commit();
+        }
+        Ok(n)
+    }
+
+    /// Commits whatever has been written to the current chunk.
+    ///
+    /// Fails if another sender reserved space after the current chunk, since
+    /// the unused part of the chunk can't be given back. The chunk stays
+    /// mapped in that case and is committed once it fills up. Dropping the
+    /// writer before then poisons the channel rather than publish the unused
+    /// part.
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(chunk) = self.chunk.as_mut() {
+            match self.sender.shrink(&chunk.cur, chunk.filled) {
+                Some(cur) => {
+                    chunk.cur = cur;
+                    self.commit();
+                }
+                None => {
+                    return Err(io::Error::other(
+                        "can't commit a partial chunk behind another reservation",
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SenderWriter {
+    fn drop(&mut self) {
+        if self.flush().is_err() {
+            // The unwritten part of the chunk can't be given back, and
+            // committing it would pass stale bytes off as data.
+            if let Some(chunk) = self.chunk.take() {
+                warn!(
+                    "SenderWriter: dropped with {} of {} bytes written behind another reservation",
+                    chunk.filled, chunk.len
+                );
+                self.sender
+                    .discard(&chunk.cur, "SenderWriter dropped a partial chunk");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::SenderWriter;
+    use crate::base::{channel, ChannelFactory, Error};
+
+    #[test]
+    fn flush_commits_partial_chunk() {
+        let (tx, mut rx) = channel(64);
+        let mut w = SenderWriter::with_chunk_size(tx, 16);
+        w.write_all(b"hello").unwrap();
+        assert!(rx.next().is_none());
+        w.flush().unwrap();
+        assert_eq!(&*rx.next().unwrap(), b"hello");
+
+        // The unused part of the chunk was given back.
+        w.write_all(b" world").unwrap();
+        w.flush().unwrap();
+        assert_eq!(&*rx.next().unwrap(), b" world");
+    }
+
+    #[test]
+    fn writes_larger_than_capacity_are_split() {
+        let (tx, mut rx) = channel(8);
+        let mut w = SenderWriter::with_chunk_size(tx, 100);
+        assert_eq!(w.chunk_size(), 8);
+
+        let data: Vec<u8> = (0..20).collect();
+        let reader = std::thread::spawn(move || {
+            let mut out = Vec::new();
+            while out.len() < 20 {
+                if let Some(r) = rx.next() {
+                    out.extend_from_slice(&r);
+                }
+            }
+            out
+        });
+        w.write_all(&data).unwrap();
+        drop(w);
+        assert_eq!(reader.join().unwrap(), data);
+    }
+
+    #[test]
+    fn dropping_a_chunk_that_cant_be_flushed_poisons_the_channel() {
+        let (tx, mut rx) = channel(64);
+        let mut other = tx.channel().sender();
+        let mut w = SenderWriter::with_chunk_size(tx, 16);
+        w.write_all(b"hello").unwrap();
+        other.map(4).unwrap().fill(1);
+        assert!(w.flush().is_err());
+
+        drop(w);
+        assert!(matches!(rx.try_next(), Err(Error::Poisoned(_))));
+    }
+}
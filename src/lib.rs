@@ -3,3 +3,5 @@
 #![allow(dead_code)]
 
 pub mod base;
+pub mod codec;
+pub mod io;